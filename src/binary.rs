@@ -0,0 +1,42 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::parser::{extract_metric_name, extract_sorted_label_key, parse_families, sample_value};
+
+pub const MAGIC: &[u8; 4] = b"PTRB";
+pub const VERSION: u8 = 1;
+
+/// Encodes every series of a rendered shard text in a compact binary form for
+/// consumers that ingest directly and don't want to re-parse exposition text.
+///
+/// Layout, all integers little-endian:
+///
+/// ```text
+/// magic     4 bytes  b"PTRB"
+/// version   u8       1
+/// count     u32      number of series
+/// series × count:
+///   name_len  u32, name       UTF-8 sample name (e.g. `http_requests_total`)
+///   key_len   u32, label_key  UTF-8 sorted `k="v"` pairs, as used for hashing
+///   value     f64
+/// ```
+///
+/// Timestamps are not carried. Unparseable values are encoded as NaN.
+pub fn encode_shard(text: &str) -> Bytes {
+    let families = parse_families(text).0;
+    let count: usize = families.iter().map(|f| f.samples.len()).sum();
+
+    let mut buf = BytesMut::with_capacity(text.len());
+    buf.put_slice(MAGIC);
+    buf.put_u8(VERSION);
+    buf.put_u32_le(count as u32);
+    for sample in families.iter().flat_map(|f| &f.samples) {
+        let name = extract_metric_name(&sample.raw_line);
+        let label_key = extract_sorted_label_key(&sample.raw_line);
+        buf.put_u32_le(name.len() as u32);
+        buf.put_slice(name.as_bytes());
+        buf.put_u32_le(label_key.len() as u32);
+        buf.put_slice(label_key.as_bytes());
+        buf.put_f64_le(sample_value(&sample.raw_line).unwrap_or(f64::NAN));
+    }
+    buf.freeze()
+}
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, bail, ensure};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tracing::{info, warn};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::config::AppConfig;
+use crate::state::{ShardData, ShardGzip, ShardedState, empty_state};
+
+const MAGIC: &[u8; 4] = b"PTRC";
+const VERSION: u8 = 1;
+
+/// Name of the cache file inside `cache_dir`.
+const CACHE_FILE: &str = "shards.cache";
+
+/// Writes the served shards of `state` to `dir`, replacing the previous cache
+/// atomically (write to a temporary file, then rename). Views are not cached;
+/// they come back with the first scrape.
+pub fn persist(dir: &Path, state: &ShardedState, fingerprint: u64) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create cache_dir {}", dir.display()))?;
+    let path = dir.join(CACHE_FILE);
+    let tmp = dir.join(format!("{CACHE_FILE}.tmp"));
+    std::fs::write(&tmp, encode(state, fingerprint))
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, &path).with_context(|| format!("failed to replace {}", path.display()))
+}
+
+/// Loads the cache written by [`persist`]. `Ok(None)` when there is no cache,
+/// when it was written for a different shard layout (`fingerprint`), or when
+/// its scrape is older than `max_age`; `Err` when it is unreadable or corrupt.
+pub fn load(
+    dir: &Path,
+    fingerprint: u64,
+    max_age: Duration,
+    now: SystemTime,
+) -> anyhow::Result<Option<ShardedState>> {
+    let path = dir.join(CACHE_FILE);
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let cached =
+        decode(Bytes::from(bytes)).with_context(|| format!("{} is corrupt", path.display()))?;
+    if cached.fingerprint != fingerprint {
+        info!("shard cache was written for a different shard layout, ignoring it");
+        return Ok(None);
+    }
+    let age = now.duration_since(cached.scraped_at).unwrap_or_default();
+    if age > max_age {
+        info!(
+            age_secs = age.as_secs(),
+            "shard cache is older than cache_max_age_secs, ignoring it"
+        );
+        return Ok(None);
+    }
+    Ok(Some(ShardedState {
+        shards: cached.shards,
+        views: BTreeMap::new(),
+        // Backdated so `/status` and the staleness checks see the cache's age.
+        last_scrape: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+        scraped_at: cached.scraped_at,
+        source_status: Vec::new(),
+        label_names: BTreeMap::new(),
+        algorithm_stats: Vec::new(),
+        families_over_limit: 0,
+        all_text: OnceLock::new(),
+    }))
+}
+
+/// The state served until the first scrape: the cached shards when
+/// `cache_dir` holds a usable cache, otherwise empty.
+pub fn initial_state(config: &AppConfig) -> Arc<ShardedState> {
+    let Some(dir) = &config.cache_dir else {
+        return empty_state();
+    };
+    let max_age = Duration::from_secs(config.cache_max_age_secs);
+    let fingerprint = config.shard_layout().fingerprint();
+    match load(dir, fingerprint, max_age, SystemTime::now()) {
+        Ok(Some(state)) => {
+            info!(
+                shards = state.shards.len(),
+                age_secs = state.last_scrape.elapsed().as_secs(),
+                "serving cached shards until the first scrape"
+            );
+            Arc::new(state)
+        }
+        Ok(None) => empty_state(),
+        Err(e) => {
+            warn!(error = format!("{e:#}"), "ignoring unusable shard cache");
+            empty_state()
+        }
+    }
+}
+
+struct Cached {
+    scraped_at: SystemTime,
+    fingerprint: u64,
+    shards: Vec<ShardData>,
+}
+
+/// Layout, all integers little-endian:
+///
+/// ```text
+/// magic        4 bytes  b"PTRC"
+/// version      u8       1
+/// scraped_at   u64      milliseconds since the epoch
+/// fingerprint  u64      ShardLayout::fingerprint of the writer
+/// count        u32      number of shards
+/// shard × count:
+///   families u64, series u64
+///   text_len u64, text
+///   gzip     u8         0 on the fly, 1 precomputed, 2 never
+///   (gzip 1) gz_len u64, gzip bytes
+/// checksum     u64      xxh3 of everything before it
+/// ```
+fn encode(state: &ShardedState, fingerprint: u64) -> Bytes {
+    let size: usize = state.shards.iter().map(|s| s.text.len() + 32).sum();
+    let mut buf = BytesMut::with_capacity(size + 32);
+    buf.put_slice(MAGIC);
+    buf.put_u8(VERSION);
+    let scraped_at = state
+        .scraped_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    buf.put_u64_le(scraped_at.as_millis() as u64);
+    buf.put_u64_le(fingerprint);
+    buf.put_u32_le(state.shards.len() as u32);
+    for shard in &state.shards {
+        buf.put_u64_le(shard.families_count as u64);
+        buf.put_u64_le(shard.series_count as u64);
+        buf.put_u64_le(shard.text.len() as u64);
+        buf.put_slice(&shard.text);
+        match &shard.gzip {
+            ShardGzip::OnTheFly => buf.put_u8(0),
+            ShardGzip::Precomputed(gz) => {
+                buf.put_u8(1);
+                buf.put_u64_le(gz.len() as u64);
+                buf.put_slice(gz);
+            }
+            ShardGzip::Never => buf.put_u8(2),
+        }
+    }
+    let checksum = xxh3_64(&buf);
+    buf.put_u64_le(checksum);
+    buf.freeze()
+}
+
+fn decode(mut bytes: Bytes) -> anyhow::Result<Cached> {
+    ensure!(bytes.len() >= MAGIC.len() + 1 + 8, "file is truncated");
+    let (body, checksum) = bytes.split_at(bytes.len() - 8);
+    ensure!(
+        xxh3_64(body) == u64::from_le_bytes(checksum.try_into()?),
+        "checksum mismatch"
+    );
+    bytes.truncate(bytes.len() - 8);
+    ensure!(bytes.starts_with(MAGIC), "not a shard cache");
+    bytes.advance(MAGIC.len());
+    let version = bytes.get_u8();
+    ensure!(version == VERSION, "unsupported cache version {version}");
+
+    let mut r = Reader(bytes);
+    let scraped_at = UNIX_EPOCH + Duration::from_millis(r.u64()?);
+    let fingerprint = r.u64()?;
+    let count = r.u32()?;
+    let mut shards = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let families = r.u64()? as usize;
+        let series = r.u64()? as usize;
+        let text = r.bytes()?;
+        let gzip = match r.u8()? {
+            0 => ShardGzip::OnTheFly,
+            1 => ShardGzip::Precomputed(r.bytes()?),
+            2 => ShardGzip::Never,
+            tag => bail!("unknown gzip tag {tag}"),
+        };
+        ensure!(
+            std::str::from_utf8(&text).is_ok(),
+            "shard text is not UTF-8"
+        );
+        shards.push(ShardData::restored(text, gzip, families, series));
+    }
+    ensure!(r.0.is_empty(), "trailing bytes after the last shard");
+    Ok(Cached {
+        scraped_at,
+        fingerprint,
+        shards,
+    })
+}
+
+/// Bounds-checked reads; `Buf` panics on a short buffer.
+struct Reader(Bytes);
+
+impl Reader {
+    fn need(&self, n: usize) -> anyhow::Result<()> {
+        ensure!(self.0.remaining() >= n, "file is truncated");
+        Ok(())
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        self.need(1)?;
+        Ok(self.0.get_u8())
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        self.need(4)?;
+        Ok(self.0.get_u32_le())
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        self.need(8)?;
+        Ok(self.0.get_u64_le())
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<Bytes> {
+        let len = self.u64()? as usize;
+        self.need(len)?;
+        Ok(self.0.split_to(len))
+    }
+}
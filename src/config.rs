@@ -1,62 +1,833 @@
-use std::collections::HashMap;
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env::VarError;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use anyhow::{Context, ensure};
-use serde::Deserialize;
+use anyhow::{Context, bail, ensure};
+use regex::Regex;
+use serde::{Deserialize, Serialize, Serializer};
+use xxhash_rust::xxh3::xxh3_64;
 
-#[derive(Debug, Deserialize)]
+use crate::hasher::{HashAlgorithm, ShardBy, ShardLayout};
+use crate::parser::DedupKeep;
+use crate::scraper::{build_client, client_builder};
+use crate::transform::{Transform, deserialize_anchored_regex, serialize_anchored_regex};
+
+/// xxh3 of a config file's bytes, to tell whether it changed on disk since
+/// it was loaded.
+pub fn file_hash(path: &Path) -> anyhow::Result<u64> {
+    let content = std::fs::read(path)
+        .with_context(|| format!("failed to read config file: {}", path.display()))?;
+    Ok(xxh3_64(&content))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct AppConfig {
-    pub listen: String,
+    pub listen: Listen,
+    /// Tokio worker threads; one per core when unset.
+    #[serde(default)]
+    pub worker_threads: Option<usize>,
     pub num_shards: u32,
+    /// Relative capacity of each shard, one entry per shard. Equal weights
+    /// when omitted.
+    #[serde(default)]
+    pub shard_weights: Option<Vec<u32>>,
+    /// Families pinned to a fixed shard, bypassing the hash. First match wins.
+    #[serde(default)]
+    pub pin: Vec<PinRule>,
+    /// Label names that alone (with the metric name) make up the shard hash
+    /// key; series that share them land on the same shard. All labels when empty.
+    #[serde(default)]
+    pub hash_only_labels: Vec<String>,
+    /// Also assigns every scrape's series with this algorithm, unserved, and
+    /// reports its balance and resize movement next to jump hash's.
+    #[serde(default)]
+    pub shadow_algorithm: Option<HashAlgorithm>,
     pub scrape_interval_secs: u64,
+    /// Each cycle's period is randomized by up to this many seconds either
+    /// way, so replicas don't hit shared upstreams in lockstep.
+    #[serde(default)]
+    pub scrape_jitter_secs: u64,
+    /// Upper bound on sources scraped at once. `0` or unset means unbounded.
+    #[serde(default)]
+    pub max_concurrent_scrapes: Option<usize>,
+    /// Upper bound on shard responses being served at once; requests over
+    /// it get 503. `0` or unset means unbounded.
+    #[serde(default)]
+    pub max_inflight_responses: Option<usize>,
+    /// Shard requests allowed per second from one client IP; requests over
+    /// it get 429. `0` or unset means unlimited.
+    #[serde(default)]
+    pub max_requests_per_sec: Option<u32>,
+    /// Mark shard responses once the data is older than `max_staleness_secs`,
+    /// as happens while every source is failing.
+    #[serde(default)]
+    pub stale_marker: bool,
+    #[serde(default = "default_max_staleness")]
+    pub max_staleness_secs: u64,
+    /// Directory where every cycle's shards are saved, and loaded from on
+    /// startup so they are served before the first scrape completes.
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+    /// A cache whose scrape is older than this is ignored on startup.
+    #[serde(default = "default_cache_max_age")]
+    pub cache_max_age_secs: u64,
+    /// Shards at least this large are gzip-compressed once per scrape cycle;
+    /// smaller ones are always served plain. Unset compresses per request.
+    #[serde(default)]
+    pub gzip_min_bytes: Option<usize>,
+    /// Threads rendering shards each cycle, and compressing them under
+    /// `gzip_min_bytes`. `0` or unset means one per CPU.
+    #[serde(default)]
+    pub compression_threads: Option<usize>,
+    /// Keep each shard's series keys so series added and removed since the
+    /// previous cycle can be reported.
+    #[serde(default)]
+    pub track_series_churn: bool,
+    /// Series with more label pairs than this are dropped at scrape time.
+    #[serde(default)]
+    pub max_labels_per_series: Option<usize>,
+    /// Distinct families kept after merging all sources; the rest, by name
+    /// order, are dropped.
+    #[serde(default)]
+    pub max_families: Option<usize>,
+    /// Drop `prom_reaper_*` families from every source, so scraping the
+    /// proxy's own `/metrics` cannot feed back into the shards.
+    #[serde(default)]
+    pub drop_self_metrics: bool,
+    #[serde(default)]
+    pub dedup_scope: DedupScope,
+    /// Which copy of a duplicate series `dedup_scope = "global"` keeps.
+    #[serde(default)]
+    pub dedup: DedupKeep,
+    /// How a counter or histogram series exposed by several sources is
+    /// combined under `dedup_scope = "global"`.
+    #[serde(default)]
+    pub merge_strategy: MergeStrategy,
+    /// What to do with a series that repeats a label name.
+    #[serde(default)]
+    pub duplicate_labels: DuplicateLabelAction,
+    /// Skip samples whose value isn't a float, `±Inf` or `NaN`, or that carry
+    /// a bad timestamp or extra tokens, instead of passing them through.
+    #[serde(default)]
+    pub strict_values: bool,
+    /// Whether series are kept when sources declare different TYPEs for a metric.
+    #[serde(default)]
+    pub on_type_conflict: TypeConflictAction,
+    /// Labels added to every series from every source, static or discovered.
+    /// A source's own `extra_labels` win on key conflicts.
+    #[serde(default)]
+    pub extra_labels: HashMap<String, String>,
+    /// Rewrite every sample with its labels in sorted order, after
+    /// `extra_labels` are added, so the served text doesn't depend on the
+    /// order upstreams emit them in.
+    #[serde(default)]
+    pub canonicalize_labels: bool,
+    /// Sample timestamps further than this from the proxy's clock are
+    /// stripped or dropped, per `timestamp_out_of_tolerance`.
+    #[serde(default)]
+    pub timestamp_tolerance_secs: Option<u64>,
+    #[serde(default)]
+    pub timestamp_out_of_tolerance: TimestampAction,
+    /// Samples whose timestamp is more than this many seconds old are
+    /// dropped; samples without a timestamp are always kept.
+    #[serde(default)]
+    pub drop_stale_samples_older_than_secs: Option<u64>,
+    #[serde(default)]
     pub sources: Vec<SourceConfig>,
+    /// Extra sharding layouts built from the same scrape, served under
+    /// `/view/{name}/metrics/shard/{id}`.
+    #[serde(default)]
+    pub view: Vec<ViewConfig>,
+    /// Prometheus HTTP service-discovery endpoints; discovered targets are
+    /// scraped alongside `sources`.
+    #[serde(default)]
+    pub http_sd: Vec<HttpSdConfig>,
+    /// Periodically pushes self-metrics to an OTLP/HTTP collector.
+    #[serde(default)]
+    pub otlp: Option<OtlpConfig>,
+    /// Prometheus remote-write receivers pushed each successful cycle's
+    /// shards, in addition to serving them for scraping.
+    #[serde(default)]
+    pub remote_write: Vec<RemoteWriteConfig>,
+    /// Proxy for `http://` upstreams. Falls back to `HTTP_PROXY` when unset.
+    #[serde(default, serialize_with = "serialize_opt_url")]
+    pub http_proxy: Option<String>,
+    /// Proxy for `https://` upstreams. Falls back to `HTTPS_PROXY` when unset.
+    #[serde(default, serialize_with = "serialize_opt_url")]
+    pub https_proxy: Option<String>,
+    /// Host suffixes that bypass the proxy. Falls back to `NO_PROXY` when empty.
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    /// `User-Agent` sent upstream. Defaults to `prom_the_reaper/<version>`; a
+    /// source's own `User-Agent` header overrides it.
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// Mount endpoints that change process state, e.g. `/debug/reset-metrics`.
+    #[serde(default)]
+    pub admin_enabled: bool,
+    /// Require HTTP basic auth on every endpoint except `/livez`.
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// Serve HTTPS with this certificate and key instead of plain HTTP.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Path every endpoint is served under, for ingresses that can't strip
+    /// it (`/reaper` serves `/reaper/metrics/shard/0`). Empty is the root.
+    #[serde(default)]
+    pub route_prefix: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SourceConfig {
+    #[serde(serialize_with = "serialize_url")]
     pub url: String,
     #[serde(default = "default_timeout")]
     pub timeout_secs: u64,
+    /// Limit on establishing the connection alone, so an unreachable host
+    /// fails fast while `timeout_secs` still bounds the whole request.
     #[serde(default)]
+    pub connect_timeout_secs: Option<u64>,
+    #[serde(default, serialize_with = "serialize_headers")]
     pub headers: HashMap<String, String>,
     /// Extra labels to attach to every time series scraped from this source.
     /// Included in the consistent-hashing key, so they affect shard assignment.
     #[serde(default)]
     pub extra_labels: HashMap<String, String>,
+    /// Ordered line-filter pipeline applied to the raw body before parsing.
+    /// `singleton_map_recursive` keeps the YAML shape (`- drop_line_regex: ...`)
+    /// the same as TOML's instead of serde_yaml's `!tag` enums.
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    pub transforms: Vec<Transform>,
+    /// Send `Accept-Encoding: gzip, br, zstd` to this source and decode
+    /// whichever the exporter picks. Disable for exporters with buggy or
+    /// CPU-heavy compression.
+    #[serde(default = "default_true")]
+    pub request_gzip: bool,
+    /// File holding a bearer token, re-read on every scrape.
+    #[serde(default)]
+    pub bearer_token_file: Option<PathBuf>,
+    /// Basic auth with the password re-read from a file on every scrape.
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuth>,
+    /// PEM client certificate for mTLS; requires `tls_client_key`.
+    #[serde(default)]
+    pub tls_client_cert: Option<PathBuf>,
+    /// PEM private key matching `tls_client_cert`.
+    #[serde(default)]
+    pub tls_client_key: Option<PathBuf>,
+    /// PEM CA bundle trusted in addition to the built-in roots.
+    #[serde(default)]
+    pub tls_ca_cert: Option<PathBuf>,
+    /// Prefer this source's HELP/TYPE over other sources' when merging.
+    #[serde(default)]
+    pub help_authority: bool,
+    /// Accept invalid/self-signed TLS certificates from this source. Lab use only.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    /// Treat a successful response with no metric families as a healthy
+    /// scrape instead of an `empty` failure.
+    #[serde(default)]
+    pub allow_empty_source: bool,
+    /// HTTP statuses meaning "nothing to export": recorded as a healthy
+    /// scrape with zero families without reading the body. `204` always is.
+    #[serde(default)]
+    pub empty_statuses: Vec<u16>,
+    /// Forward proxy for this source only (all schemes), replacing the global
+    /// `http_proxy`/`https_proxy`. Credentials may be given in the URL.
+    #[serde(default, serialize_with = "serialize_opt_url")]
+    pub proxy: Option<String>,
+    /// What to do with a body that is not valid UTF-8.
+    #[serde(default)]
+    pub on_invalid_utf8: InvalidUtf8Action,
+    /// Offer the protobuf exposition format in `Accept`, with text as the
+    /// fallback. A protobuf response is decoded into text before parsing.
+    #[serde(default)]
+    pub accept_protobuf: bool,
+}
+
+impl SourceConfig {
+    /// A source with every optional setting at its config-file default.
+    pub fn new(url: &str, timeout_secs: u64) -> Self {
+        Self {
+            url: url.to_owned(),
+            timeout_secs,
+            connect_timeout_secs: None,
+            headers: HashMap::new(),
+            extra_labels: HashMap::new(),
+            transforms: Vec::new(),
+            request_gzip: true,
+            bearer_token_file: None,
+            basic_auth: None,
+            tls_client_cert: None,
+            tls_client_key: None,
+            tls_ca_cert: None,
+            help_authority: false,
+            insecure_skip_verify: false,
+            allow_empty_source: false,
+            empty_statuses: Vec::new(),
+            proxy: None,
+            on_invalid_utf8: InvalidUtf8Action::Lossy,
+            accept_protobuf: false,
+        }
+    }
+}
+
+/// Whether identical series from different sources are deduplicated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupScope {
+    /// First source wins for each `(family, labels)` across all sources.
+    #[default]
+    Global,
+    /// Keep every series; sources are told apart by their `extra_labels`.
+    None,
+}
+
+/// How identical counter and histogram series from several sources combine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategy {
+    /// Keep the first source's sample, as for every other type.
+    #[default]
+    FirstWins,
+    /// Add the values up; other types stay first-wins.
+    Sum,
+}
+
+/// What to do with a series whose label set repeats a name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLabelAction {
+    /// Keep the last occurrence of each name, as Prometheus would.
+    #[default]
+    KeepLast,
+    /// Drop the series.
+    Drop,
+}
+
+/// What to do when sources declare different TYPEs for the same metric name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TypeConflictAction {
+    /// Serve every series under the TYPE that won the merge.
+    #[default]
+    KeepFirst,
+    /// Drop the series of the family whose TYPE lost.
+    Drop,
+}
+
+/// What to do with a source body containing invalid UTF-8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidUtf8Action {
+    /// Replace each invalid sequence with U+FFFD and parse the rest.
+    #[default]
+    Lossy,
+    /// Fail the scrape.
+    Fail,
+}
+
+/// What to do with a sample whose timestamp is outside `timestamp_tolerance_secs`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampAction {
+    /// Keep the sample and let Prometheus assign the scrape time.
+    #[default]
+    Strip,
+    /// Drop the sample.
+    Drop,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password_file: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PinRule {
+    /// Matched against the whole family name (implicitly anchored).
+    #[serde(
+        deserialize_with = "deserialize_anchored_regex",
+        serialize_with = "serialize_anchored_regex"
+    )]
+    pub regex: Regex,
+    pub shard: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ViewConfig {
+    /// URL path segment: `[a-zA-Z0-9_-]+`.
+    pub name: String,
+    pub num_shards: u32,
+    #[serde(default)]
+    pub shard_weights: Option<Vec<u32>>,
+    #[serde(default)]
+    pub shard_by: ShardBy,
+    /// xxh3 seed; a different seed gives an unrelated but equally stable assignment.
+    #[serde(default)]
+    pub seed: u64,
+}
+
+impl ViewConfig {
+    pub fn shard_layout(&self) -> ShardLayout {
+        let layout = match &self.shard_weights {
+            Some(weights) => ShardLayout::weighted(weights),
+            None => ShardLayout::uniform(self.num_shards),
+        };
+        layout.with_seed(self.seed).with_shard_by(self.shard_by)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HttpSdConfig {
+    /// Endpoint returning Prometheus http_sd JSON: `[{"targets": [...], "labels": {...}}]`.
+    #[serde(serialize_with = "serialize_url")]
+    pub url: String,
+    #[serde(default = "default_sd_refresh")]
+    pub refresh_secs: u64,
+    /// Timeout applied to the SD request and to every discovered target.
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RemoteWriteConfig {
+    /// Receiver endpoint, e.g. `http://mimir:9009/api/v1/push`.
+    #[serde(serialize_with = "serialize_url")]
+    pub url: String,
+    /// Shards pushed here: one id (`"2"`) or an inclusive range (`"0-3"`),
+    /// matching `/metrics/shards/{start}-{end}`. Every shard when unset.
+    #[serde(default)]
+    pub shards: Option<String>,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+    /// Extra request headers, e.g. `Authorization` or `X-Scope-OrgID`.
+    #[serde(default, serialize_with = "serialize_headers")]
+    pub headers: HashMap<String, String>,
+}
+
+impl RemoteWriteConfig {
+    /// The shards `shards` names out of `num_shards`, or `None` when it is
+    /// malformed or out of range.
+    pub fn shard_range(&self, num_shards: u32) -> Option<RangeInclusive<u32>> {
+        let (start, end) = match self.shards.as_deref() {
+            None => (0, num_shards.checked_sub(1)?),
+            Some(spec) => match spec.split_once('-') {
+                Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+                None => {
+                    let id = spec.trim().parse().ok()?;
+                    (id, id)
+                }
+            },
+        };
+        (start <= end && end < num_shards).then_some(start..=end)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthConfig {
+    pub username: String,
+    #[serde(serialize_with = "serialize_redacted")]
+    pub password: String,
+}
+
+/// Address(es) the HTTP server binds: `listen = "0.0.0.0:9090"` or
+/// `listen = ["10.0.0.5:9090", "[fd00::5]:9090"]`. Every address serves the
+/// same router.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Listen {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Listen {
+    pub fn addrs(&self) -> &[String] {
+        match self {
+            Listen::One(addr) => std::slice::from_ref(addr),
+            Listen::Many(addrs) => addrs,
+        }
+    }
+}
+
+impl std::fmt::Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.addrs().join(", "))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert_file: PathBuf,
+    /// PEM private key: PKCS#8, PKCS#1 RSA or SEC1 EC.
+    pub key_file: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OtlpConfig {
+    /// Collector base URL; metrics are POSTed to `{endpoint}/v1/metrics`.
+    #[serde(serialize_with = "serialize_url")]
+    pub endpoint: String,
+    #[serde(default = "default_otlp_interval")]
+    pub interval_secs: u64,
+}
+
+/// Stands in for a secret in `/config`, and for URL credentials everywhere.
+const REDACTED: &str = "***";
+
+/// Largest `shard_weights` sum: the layout holds one virtual bucket per unit
+/// of weight.
+const MAX_WEIGHT_TOTAL: u64 = 65536;
+
+fn weight_total(weights: &[u32]) -> u64 {
+    weights.iter().map(|&w| u64::from(w)).sum()
+}
+
+fn serialize_redacted<S: Serializer>(_: &str, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(REDACTED)
+}
+
+/// Header values often carry credentials (`Authorization`, API tokens), so
+/// only the names are shown; sorted for a stable `/config`.
+fn serialize_headers<S: Serializer>(
+    headers: &HashMap<String, String>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.collect_map(
+        headers
+            .keys()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|k| (k, REDACTED)),
+    )
+}
+
+/// Replaces a URL's userinfo with `***` (`http://***@host:9100/metrics`) so
+/// credentials don't reach logs, `/status`, `/metrics` labels or `/config`.
+/// URLs without userinfo, and unparsable ones, are returned unchanged.
+pub fn redact_url(url: &str) -> Cow<'_, str> {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if !parsed.username().is_empty() || parsed.password().is_some() => {
+            let _ = parsed.set_password(None);
+            let _ = parsed.set_username(REDACTED);
+            Cow::Owned(parsed.into())
+        }
+        _ => Cow::Borrowed(url),
+    }
+}
+
+fn serialize_url<S: Serializer>(url: &str, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&redact_url(url))
+}
+
+fn serialize_opt_url<S: Serializer>(url: &Option<String>, s: S) -> Result<S::Ok, S::Error> {
+    match url {
+        Some(url) => s.serialize_some(&redact_url(url)),
+        None => s.serialize_none(),
+    }
+}
+
+fn default_otlp_interval() -> u64 {
+    60
+}
+
+fn default_max_staleness() -> u64 {
+    300
+}
+
+fn default_cache_max_age() -> u64 {
+    600
 }
 
 fn default_timeout() -> u64 {
     30
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_sd_refresh() -> u64 {
+    60
+}
+
 impl AppConfig {
+    /// A config holding just `source`, with every global setting at its
+    /// default. Proxies still fall back to the environment.
+    pub fn single_source(source: SourceConfig) -> Self {
+        Self {
+            listen: Listen::One("127.0.0.1:0".to_owned()),
+            worker_threads: None,
+            num_shards: 1,
+            shard_weights: None,
+            pin: Vec::new(),
+            hash_only_labels: Vec::new(),
+            shadow_algorithm: None,
+            scrape_interval_secs: 30,
+            scrape_jitter_secs: 0,
+            max_concurrent_scrapes: None,
+            max_inflight_responses: None,
+            max_requests_per_sec: None,
+            stale_marker: false,
+            max_staleness_secs: default_max_staleness(),
+            cache_dir: None,
+            cache_max_age_secs: default_cache_max_age(),
+            gzip_min_bytes: None,
+            compression_threads: None,
+            track_series_churn: false,
+            max_labels_per_series: None,
+            max_families: None,
+            drop_self_metrics: false,
+            dedup_scope: DedupScope::Global,
+            dedup: DedupKeep::First,
+            merge_strategy: MergeStrategy::FirstWins,
+            duplicate_labels: DuplicateLabelAction::KeepLast,
+            strict_values: false,
+            on_type_conflict: TypeConflictAction::KeepFirst,
+            extra_labels: HashMap::new(),
+            canonicalize_labels: false,
+            timestamp_tolerance_secs: None,
+            timestamp_out_of_tolerance: TimestampAction::Strip,
+            drop_stale_samples_older_than_secs: None,
+            sources: vec![source],
+            view: Vec::new(),
+            http_sd: Vec::new(),
+            otlp: None,
+            remote_write: Vec::new(),
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: Vec::new(),
+            user_agent: None,
+            admin_enabled: false,
+            auth: None,
+            tls: None,
+            route_prefix: String::new(),
+        }
+    }
+
     pub fn load(path: &Path) -> anyhow::Result<Self> {
         let content = std::fs::read_to_string(path)
             .with_context(|| format!("failed to read config file: {}", path.display()))?;
-        let config: AppConfig =
-            toml::from_str(&content).with_context(|| "failed to parse config file")?;
+        // Anything other than .yaml/.yml is TOML, as before YAML support.
+        let is_yaml = path
+            .extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "yml");
+        let mut config: AppConfig = if is_yaml {
+            serde_yaml::from_str(&content).with_context(|| "failed to parse config file")?
+        } else {
+            toml::from_str(&content).with_context(|| "failed to parse config file")?
+        };
+        config.apply_env_overrides()?;
         config.validate()?;
         Ok(config)
     }
 
+    /// Overlays `PROM_REAPER_*` environment variables on the file values.
+    fn apply_env_overrides(&mut self) -> anyhow::Result<()> {
+        if let Some(listen) = env_override("PROM_REAPER_LISTEN")? {
+            self.listen = Listen::One(listen);
+        }
+        if let Some(num_shards) = env_override("PROM_REAPER_NUM_SHARDS")? {
+            self.num_shards = num_shards;
+        }
+        if let Some(interval) = env_override("PROM_REAPER_SCRAPE_INTERVAL_SECS")? {
+            self.scrape_interval_secs = interval;
+        }
+        Ok(())
+    }
+
+    pub fn shard_layout(&self) -> ShardLayout {
+        let layout = match &self.shard_weights {
+            Some(weights) => ShardLayout::weighted(weights),
+            None => ShardLayout::uniform(self.num_shards),
+        };
+        layout
+            .with_pins(
+                self.pin
+                    .iter()
+                    .map(|p| (p.regex.clone(), p.shard))
+                    .collect(),
+            )
+            .with_hash_labels(self.hash_only_labels.clone())
+    }
+
+    /// Layouts of the configured `[[view]]`s, by name.
+    pub fn view_layouts(&self) -> BTreeMap<String, ShardLayout> {
+        self.view
+            .iter()
+            .map(|v| (v.name.clone(), v.shard_layout()))
+            .collect()
+    }
+
     fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            !self.listen.addrs().is_empty(),
+            "listen must name at least one address"
+        );
+        let mut seen_listen = HashSet::new();
+        for listen in self.listen.addrs() {
+            ensure!(
+                is_valid_listen_addr(listen),
+                "listen {listen:?} must be an address like 0.0.0.0:9090 or host:port"
+            );
+            ensure!(
+                seen_listen.insert(listen),
+                "listen {listen:?} is given more than once"
+            );
+        }
         ensure!(self.num_shards > 0, "num_shards must be greater than 0");
-        ensure!(!self.sources.is_empty(), "at least one source is required");
+        ensure!(
+            self.worker_threads != Some(0),
+            "worker_threads must be greater than 0"
+        );
+        if let Some(weights) = &self.shard_weights {
+            ensure!(
+                weights.len() == self.num_shards as usize,
+                "shard_weights has {} entries but num_shards is {}",
+                weights.len(),
+                self.num_shards
+            );
+            ensure!(
+                weights.iter().all(|&w| w > 0),
+                "shard_weights entries must be greater than 0"
+            );
+            ensure!(
+                weight_total(weights) <= MAX_WEIGHT_TOTAL,
+                "shard_weights must sum to at most {MAX_WEIGHT_TOTAL}, got {}",
+                weight_total(weights)
+            );
+        }
+        ensure!(
+            !self.sources.is_empty() || !self.http_sd.is_empty(),
+            "at least one source or http_sd endpoint is required"
+        );
         ensure!(
             self.scrape_interval_secs > 0,
             "scrape_interval_secs must be greater than 0"
         );
+        ensure!(
+            self.merge_strategy == MergeStrategy::FirstWins
+                || self.dedup_scope == DedupScope::Global,
+            "merge_strategy = \"sum\" requires dedup_scope = \"global\""
+        );
+        ensure!(
+            !self.stale_marker || self.max_staleness_secs > 0,
+            "max_staleness_secs must be greater than 0"
+        );
+        ensure!(
+            self.scrape_jitter_secs < self.scrape_interval_secs,
+            "scrape_jitter_secs ({}) must be less than scrape_interval_secs ({})",
+            self.scrape_jitter_secs,
+            self.scrape_interval_secs
+        );
+        for (name, proxy) in [
+            ("http_proxy", &self.http_proxy),
+            ("https_proxy", &self.https_proxy),
+        ] {
+            if let Some(proxy) = proxy {
+                let url = reqwest::Url::parse(proxy).with_context(|| {
+                    format!("{name} {:?} is not a valid URL", redact_url(proxy))
+                })?;
+                ensure!(
+                    matches!(url.scheme(), "http" | "https"),
+                    "{} {:?} must use http:// or https://",
+                    name,
+                    redact_url(proxy)
+                );
+            }
+        }
+        client_builder(self)?
+            .build()
+            .context("failed to build HTTP client with proxy settings")?;
+        for name in self.extra_labels.keys() {
+            ensure!(
+                is_valid_label_name(name),
+                "extra_labels: {:?} is not a valid Prometheus label name \
+                 (must match [a-zA-Z_][a-zA-Z0-9_]*)",
+                name
+            );
+        }
         for (i, source) in self.sources.iter().enumerate() {
             ensure!(
                 !source.url.is_empty(),
                 "source[{}] url must not be empty",
                 i
             );
+            if source.url.starts_with("unix:") {
+                #[cfg(unix)]
+                ensure!(
+                    crate::unix_socket::parse_unix_url(&source.url).is_some(),
+                    "source[{}] url {:?} must look like unix:///path/to.sock:/metrics",
+                    i,
+                    redact_url(&source.url)
+                );
+                #[cfg(not(unix))]
+                bail!(
+                    "source[{}] url {:?}: unix sockets are not supported on this platform",
+                    i,
+                    redact_url(&source.url)
+                );
+            } else {
+                let url = reqwest::Url::parse(&source.url).with_context(|| {
+                    format!(
+                        "source[{}] url {:?} is not a valid URL",
+                        i,
+                        redact_url(&source.url)
+                    )
+                })?;
+                ensure!(
+                    matches!(url.scheme(), "http" | "https") && url.host_str().is_some(),
+                    "source[{}] url {:?} must be an http:// or https:// URL with a host",
+                    i,
+                    redact_url(&source.url)
+                );
+            }
             ensure!(
                 source.timeout_secs > 0,
                 "source[{}] timeout_secs must be greater than 0",
                 i
             );
+            if let Some(connect) = source.connect_timeout_secs {
+                ensure!(
+                    connect > 0 && connect <= source.timeout_secs,
+                    "source[{}] connect_timeout_secs must be between 1 and timeout_secs ({})",
+                    i,
+                    source.timeout_secs
+                );
+            }
+            ensure!(
+                source.bearer_token_file.is_none() || source.basic_auth.is_none(),
+                "source[{}] bearer_token_file and basic_auth are mutually exclusive",
+                i
+            );
+            ensure!(
+                source.tls_client_cert.is_some() == source.tls_client_key.is_some(),
+                "source[{}] tls_client_cert and tls_client_key must be set together",
+                i
+            );
+            if let Some(proxy) = &source.proxy {
+                let url = reqwest::Url::parse(proxy).with_context(|| {
+                    format!(
+                        "source[{}] proxy {:?} is not a valid URL",
+                        i,
+                        redact_url(proxy)
+                    )
+                })?;
+                ensure!(
+                    matches!(url.scheme(), "http" | "https"),
+                    "source[{}] proxy {:?} must use http:// or https://",
+                    i,
+                    redact_url(proxy)
+                );
+            }
+            // Load TLS material now so bad paths or PEM fail at startup, not per scrape.
+            build_client(self, source)
+                .with_context(|| format!("source[{}] {}", i, redact_url(&source.url)))?;
             for name in source.extra_labels.keys() {
                 ensure!(
                     is_valid_label_name(name),
@@ -67,12 +838,179 @@ impl AppConfig {
                 );
             }
         }
+        for (i, rule) in self.pin.iter().enumerate() {
+            ensure!(
+                rule.shard < self.num_shards,
+                "pin[{}] shard {} is out of range, num_shards is {}",
+                i,
+                rule.shard,
+                self.num_shards
+            );
+        }
+        for name in &self.hash_only_labels {
+            ensure!(
+                is_valid_label_name(name),
+                "hash_only_labels: {:?} is not a valid Prometheus label name \
+                 (must match [a-zA-Z_][a-zA-Z0-9_]*)",
+                name
+            );
+        }
+        let mut view_names = HashSet::new();
+        for (i, view) in self.view.iter().enumerate() {
+            ensure!(
+                !view.name.is_empty()
+                    && view
+                        .name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+                "view[{}] name {:?} must match [a-zA-Z0-9_-]+",
+                i,
+                view.name
+            );
+            ensure!(
+                view_names.insert(view.name.as_str()),
+                "view[{}] name {:?} is used more than once",
+                i,
+                view.name
+            );
+            ensure!(
+                view.num_shards > 0,
+                "view[{}] num_shards must be greater than 0",
+                i
+            );
+            if let Some(weights) = &view.shard_weights {
+                ensure!(
+                    weights.len() == view.num_shards as usize,
+                    "view[{}] shard_weights has {} entries but num_shards is {}",
+                    i,
+                    weights.len(),
+                    view.num_shards
+                );
+                ensure!(
+                    weights.iter().all(|&w| w > 0),
+                    "view[{}] shard_weights entries must be greater than 0",
+                    i
+                );
+                ensure!(
+                    weight_total(weights) <= MAX_WEIGHT_TOTAL,
+                    "view[{}] shard_weights must sum to at most {MAX_WEIGHT_TOTAL}, got {}",
+                    i,
+                    weight_total(weights)
+                );
+            }
+        }
+        for (i, sd) in self.http_sd.iter().enumerate() {
+            ensure!(!sd.url.is_empty(), "http_sd[{}] url must not be empty", i);
+            ensure!(
+                sd.refresh_secs > 0,
+                "http_sd[{}] refresh_secs must be greater than 0",
+                i
+            );
+            ensure!(
+                sd.timeout_secs > 0,
+                "http_sd[{}] timeout_secs must be greater than 0",
+                i
+            );
+        }
+        for (i, rw) in self.remote_write.iter().enumerate() {
+            let url = reqwest::Url::parse(&rw.url).with_context(|| {
+                format!(
+                    "remote_write[{i}] url {:?} is not a valid URL",
+                    redact_url(&rw.url)
+                )
+            })?;
+            ensure!(
+                matches!(url.scheme(), "http" | "https") && url.host_str().is_some(),
+                "remote_write[{i}] url {:?} must be an http:// or https:// URL with a host",
+                redact_url(&rw.url)
+            );
+            ensure!(
+                rw.timeout_secs > 0,
+                "remote_write[{i}] timeout_secs must be greater than 0"
+            );
+            ensure!(
+                rw.shard_range(self.num_shards).is_some(),
+                "remote_write[{i}] shards {:?} must be a shard id or range like 0-3 below num_shards {}",
+                rw.shards.as_deref().unwrap_or_default(),
+                self.num_shards
+            );
+        }
+        ensure!(
+            self.cache_max_age_secs > 0,
+            "cache_max_age_secs must be greater than 0"
+        );
+        if let Some(tls) = &self.tls {
+            crate::tls::server_config(tls)?;
+        }
+        ensure!(
+            !self
+                .route_prefix
+                .contains(|c: char| matches!(c, '{' | '}' | '*' | '?' | '#') || c.is_whitespace()),
+            "route_prefix {:?} must be a plain path like /reaper",
+            self.route_prefix
+        );
+        if let Some(auth) = &self.auth {
+            ensure!(
+                !auth.username.is_empty() && !auth.username.contains(':'),
+                "auth username must be non-empty and must not contain ':'"
+            );
+            ensure!(!auth.password.is_empty(), "auth password must not be empty");
+        }
+        if let Some(otlp) = &self.otlp {
+            let url = reqwest::Url::parse(&otlp.endpoint).with_context(|| {
+                format!(
+                    "otlp endpoint {:?} is not a valid URL",
+                    redact_url(&otlp.endpoint)
+                )
+            })?;
+            ensure!(
+                matches!(url.scheme(), "http" | "https") && url.host_str().is_some(),
+                "otlp endpoint {:?} must be an http:// or https:// URL with a host",
+                redact_url(&otlp.endpoint)
+            );
+            ensure!(
+                otlp.interval_secs > 0,
+                "otlp interval_secs must be greater than 0"
+            );
+        }
         Ok(())
     }
 }
 
+/// Reads and parses an override variable; unset means no override.
+fn env_override<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(e) => bail!("environment variable {name}={value:?} is invalid: {e}"),
+        },
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => bail!("environment variable {name} is not valid UTF-8"),
+    }
+}
+
+/// Accepts a socket address (`0.0.0.0:9090`, `[::]:9090`) or `host:port`
+/// with a plain DNS hostname.
+fn is_valid_listen_addr(s: &str) -> bool {
+    if s.parse::<std::net::SocketAddr>().is_ok() {
+        return true;
+    }
+    let Some((host, port)) = s.rsplit_once(':') else {
+        return false;
+    };
+    port.parse::<u16>().is_ok()
+        && !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
 /// Validates that a string is a legal Prometheus label name: `[a-zA-Z_][a-zA-Z0-9_]*`.
-fn is_valid_label_name(s: &str) -> bool {
+pub(crate) fn is_valid_label_name(s: &str) -> bool {
     let mut chars = s.chars();
     match chars.next() {
         None => false,
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use reqwest::Client;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::config::{HttpSdConfig, SourceConfig, is_valid_label_name, redact_url};
+
+/// One target group in the Prometheus http_sd response format.
+#[derive(Deserialize)]
+struct TargetGroup {
+    targets: Vec<String>,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+}
+
+/// Targets discovered from one `http_sd` endpoint, plus when they were fetched.
+struct SdState {
+    targets: Vec<Arc<SourceConfig>>,
+    refreshed_at: Option<Instant>,
+}
+
+/// Tracks all configured `http_sd` endpoints and refreshes each one once its
+/// `refresh_secs` has elapsed. On a failed refresh the previously discovered
+/// targets are kept, mirroring the stale-data policy for scrapes.
+pub struct Discovery {
+    endpoints: Vec<(HttpSdConfig, SdState)>,
+}
+
+impl Discovery {
+    pub fn new(configs: &[HttpSdConfig]) -> Self {
+        let endpoints = configs
+            .iter()
+            .map(|c| {
+                (
+                    c.clone(),
+                    SdState {
+                        targets: Vec::new(),
+                        refreshed_at: None,
+                    },
+                )
+            })
+            .collect();
+        Self { endpoints }
+    }
+
+    /// Refreshes every endpoint that is due and returns all currently known targets.
+    pub async fn targets(&mut self, client: &Client) -> Vec<Arc<SourceConfig>> {
+        for (sd, sd_state) in &mut self.endpoints {
+            let due = sd_state
+                .refreshed_at
+                .is_none_or(|t| t.elapsed() >= Duration::from_secs(sd.refresh_secs));
+            if !due {
+                continue;
+            }
+            match fetch_targets(client, sd).await {
+                Ok(targets) => {
+                    info!(url = %redact_url(&sd.url), targets = targets.len(), "refreshed http_sd");
+                    sd_state.targets = targets;
+                }
+                Err(e) => {
+                    warn!(url = %redact_url(&sd.url), error = %e, "http_sd refresh failed, keeping previous targets");
+                }
+            }
+            sd_state.refreshed_at = Some(Instant::now());
+        }
+
+        self.endpoints
+            .iter()
+            .flat_map(|(_, s)| s.targets.iter().cloned())
+            .collect()
+    }
+}
+
+/// Fetches and converts one endpoint's target groups. Errors leave out the
+/// URL, which may carry credentials.
+pub async fn fetch_targets(
+    client: &Client,
+    sd: &HttpSdConfig,
+) -> anyhow::Result<Vec<Arc<SourceConfig>>> {
+    let body = client
+        .get(&sd.url)
+        .timeout(Duration::from_secs(sd.timeout_secs))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(reqwest::Error::without_url)?
+        .text()
+        .await
+        .map_err(reqwest::Error::without_url)?;
+    let groups: Vec<TargetGroup> = serde_json::from_str(&body)?;
+    Ok(groups_to_sources(groups, sd.timeout_secs))
+}
+
+/// Converts http_sd target groups into scrape sources.
+///
+/// `__scheme__` and `__metrics_path__` shape the URL (defaults `http` and
+/// `/metrics`); other `__`-prefixed meta labels are dropped, and the rest
+/// become the source's `extra_labels`.
+fn groups_to_sources(groups: Vec<TargetGroup>, timeout_secs: u64) -> Vec<Arc<SourceConfig>> {
+    let mut sources = Vec::new();
+    for group in groups {
+        let scheme = group
+            .labels
+            .get("__scheme__")
+            .map(String::as_str)
+            .unwrap_or("http");
+        let path = group
+            .labels
+            .get("__metrics_path__")
+            .map(String::as_str)
+            .unwrap_or("/metrics");
+        let extra_labels: HashMap<String, String> = group
+            .labels
+            .iter()
+            .filter(|(k, _)| !k.starts_with("__") && is_valid_label_name(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        for target in &group.targets {
+            sources.push(Arc::new(SourceConfig {
+                extra_labels: extra_labels.clone(),
+                ..SourceConfig::new(&format!("{scheme}://{target}{path}"), timeout_secs)
+            }));
+        }
+    }
+    sources
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_to_sources_builds_urls_and_labels() {
+        let groups: Vec<TargetGroup> = serde_json::from_str(
+            r#"[
+                {"targets": ["a:9100", "b:9100"], "labels": {"env": "prod", "__meta_x": "y"}},
+                {"targets": ["c:443"], "labels": {"__scheme__": "https", "__metrics_path__": "/m"}}
+            ]"#,
+        )
+        .unwrap();
+        let sources = groups_to_sources(groups, 5);
+        let urls: Vec<_> = sources.iter().map(|s| s.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "http://a:9100/metrics",
+                "http://b:9100/metrics",
+                "https://c:443/m"
+            ]
+        );
+        assert_eq!(sources[0].extra_labels.len(), 1);
+        assert_eq!(sources[0].extra_labels["env"], "prod");
+        assert!(sources[2].extra_labels.is_empty());
+        assert_eq!(sources[2].timeout_secs, 5);
+    }
+}
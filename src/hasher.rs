@@ -1,13 +1,239 @@
-use xxhash_rust::xxh3::Xxh3;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::{Xxh3, xxh3_64_with_seed};
+
+use crate::parser::extract_label_subset_key;
 
 /// Assigns a metric series to a shard by hashing `name\x00label_key` without
-/// allocating an intermediate String.
+/// allocating an intermediate String. Serving goes through [`ShardLayout`];
+/// this is the uniform, unseeded reference the tests compare against.
+#[cfg(test)]
 pub fn assign_shard_from_parts(name: &str, label_key: &str, num_shards: u32) -> u32 {
-    let mut h = Xxh3::new();
+    assign_seeded(name, label_key, 0, num_shards)
+}
+
+/// Jump-hashes `name\x00label_key` under an xxh3 seed; seed 0 is the unseeded hash.
+fn assign_seeded(name: &str, label_key: &str, seed: u64, num_shards: u32) -> u32 {
+    jump_consistent_hash(series_hash(name, label_key, seed), num_shards)
+}
+
+/// xxh3 of `name\x00label_key`, identifying a series.
+pub fn series_hash(name: &str, label_key: &str, seed: u64) -> u64 {
+    let mut h = Xxh3::with_seed(seed);
     h.update(name.as_bytes());
     h.update(b"\x00");
     h.update(label_key.as_bytes());
-    jump_consistent_hash(h.digest(), num_shards)
+    h.digest()
+}
+
+/// What a shard assignment is keyed on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardBy {
+    /// Every series is hashed independently (`name + sorted labels`).
+    #[default]
+    Series,
+    /// Whole families are hashed by family name and stay together.
+    Family,
+}
+
+/// Maps the consistent-hash space onto physical shards.
+///
+/// Each physical shard owns `weight` contiguous virtual buckets; series are
+/// jump-hashed over the virtual buckets and then mapped back. With all
+/// weights equal to 1 this is exactly `assign_shard_from_parts(.., num_shards)`,
+/// and appending a shard only appends buckets, so jump hash's minimal-movement
+/// property still holds when growing the shard list.
+#[derive(Debug, Clone)]
+pub struct ShardLayout {
+    /// `buckets[virtual_bucket] = physical_shard`.
+    buckets: Vec<u32>,
+    num_shards: u32,
+    /// Family-name rules consulted before hashing; first match wins.
+    pins: Vec<(Regex, u32)>,
+    seed: u64,
+    shard_by: ShardBy,
+    /// When non-empty, only these labels (sorted, deduplicated) are hashed.
+    hash_labels: Vec<String>,
+}
+
+impl ShardLayout {
+    /// Equal weights: one virtual bucket per shard.
+    pub fn uniform(num_shards: u32) -> Self {
+        Self {
+            buckets: (0..num_shards).collect(),
+            num_shards,
+            pins: Vec::new(),
+            seed: 0,
+            shard_by: ShardBy::Series,
+            hash_labels: Vec::new(),
+        }
+    }
+
+    /// One entry per physical shard; shard `i` receives `weights[i] / sum(weights)`
+    /// of the series.
+    pub fn weighted(weights: &[u32]) -> Self {
+        let buckets = weights
+            .iter()
+            .enumerate()
+            .flat_map(|(shard, &w)| std::iter::repeat_n(shard as u32, w as usize))
+            .collect();
+        Self {
+            buckets,
+            num_shards: weights.len() as u32,
+            pins: Vec::new(),
+            seed: 0,
+            shard_by: ShardBy::Series,
+            hash_labels: Vec::new(),
+        }
+    }
+
+    /// Adds family pin rules. Pinned shard ids must be `< num_shards`.
+    pub fn with_pins(mut self, pins: Vec<(Regex, u32)>) -> Self {
+        self.pins = pins;
+        self
+    }
+
+    /// Seeds the hash, giving an independent but equally consistent assignment.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_shard_by(mut self, shard_by: ShardBy) -> Self {
+        self.shard_by = shard_by;
+        self
+    }
+
+    /// Hashes series on their name plus only these labels, so series that
+    /// agree on them land together whatever their other labels.
+    pub fn with_hash_labels(mut self, mut labels: Vec<String>) -> Self {
+        labels.sort_unstable();
+        labels.dedup();
+        self.hash_labels = labels;
+        self
+    }
+
+    /// The same layout over `num_shards` physical shards: existing shards keep
+    /// their weights, added shards get weight 1 and removed shards are
+    /// dropped. Pins, seed, `shard_by` and hash labels are kept as they are.
+    pub fn resized(&self, num_shards: u32) -> Self {
+        let weights: Vec<u32> = (0..num_shards)
+            .map(|shard| {
+                if shard < self.num_shards {
+                    self.buckets.iter().filter(|&&b| b == shard).count() as u32
+                } else {
+                    1
+                }
+            })
+            .collect();
+        let mut layout = self.clone();
+        layout.buckets = Self::weighted(&weights).buckets;
+        layout.num_shards = num_shards;
+        layout
+    }
+
+    /// The shard every series of `family` goes to, when that is decided per
+    /// family: by a pin rule, or by hashing the name under [`ShardBy::Family`].
+    pub fn family_shard(&self, family: &str) -> Option<u32> {
+        self.pinned_shard(family).or_else(|| match self.shard_by {
+            ShardBy::Family => Some(self.assign(family, "")),
+            ShardBy::Series => None,
+        })
+    }
+
+    /// Returns the shard a family is pinned to, if any rule matches.
+    pub fn pinned_shard(&self, family: &str) -> Option<u32> {
+        self.pins
+            .iter()
+            .find(|(re, _)| re.is_match(family))
+            .map(|&(_, shard)| shard)
+    }
+
+    /// The family pin rules, in match order.
+    pub fn pins(&self) -> &[(Regex, u32)] {
+        &self.pins
+    }
+
+    pub fn num_shards(&self) -> u32 {
+        self.num_shards
+    }
+
+    /// A hash of everything that decides placement: weights, pins, seed,
+    /// `shard_by` and the hashed labels. Two layouts with equal fingerprints
+    /// assign every series to the same shard, so a changed value marks a
+    /// reshard.
+    pub fn fingerprint(&self) -> u64 {
+        let mut h = Xxh3::new();
+        for bucket in &self.buckets {
+            h.update(&bucket.to_le_bytes());
+        }
+        h.update(b"\x00");
+        for (re, shard) in &self.pins {
+            h.update(re.as_str().as_bytes());
+            h.update(b"\x00");
+            h.update(&shard.to_le_bytes());
+        }
+        h.update(b"\x00");
+        h.update(&self.seed.to_le_bytes());
+        h.update(&[self.shard_by as u8]);
+        for label in &self.hash_labels {
+            h.update(label.as_bytes());
+            h.update(b"\x00");
+        }
+        h.digest()
+    }
+
+    /// Returns the physical shard for a series.
+    pub fn assign(&self, name: &str, label_key: &str) -> u32 {
+        let bucket = assign_seeded(name, label_key, self.seed, self.buckets.len() as u32);
+        self.buckets[bucket as usize]
+    }
+
+    /// Returns the physical shard for the series on a sample `line`, whose
+    /// full sorted label key is `label_key`. With hash labels set, the key is
+    /// rebuilt from just those labels.
+    pub fn assign_line(&self, name: &str, line: &str, label_key: &str) -> u32 {
+        if self.hash_labels.is_empty() {
+            self.assign(name, label_key)
+        } else {
+            self.assign(name, &extract_label_subset_key(line, &self.hash_labels))
+        }
+    }
+}
+
+/// A series-hash-to-shard algorithm. Serving always uses [`Jump`]; others are
+/// only evaluated in shadow via `shadow_algorithm`.
+///
+/// [`Jump`]: HashAlgorithm::Jump
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    /// Jump consistent hash, as served.
+    Jump,
+    /// Rendezvous (highest random weight): each shard scores the key and the
+    /// highest score wins. O(n) per key.
+    Rendezvous,
+}
+
+impl HashAlgorithm {
+    /// Stable snake_case name, used as the `algorithm` label.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Jump => "jump",
+            HashAlgorithm::Rendezvous => "rendezvous",
+        }
+    }
+
+    /// The shard for `key` among `num_shards` equal shards.
+    pub fn shard(self, key: u64, num_shards: u32) -> u32 {
+        match self {
+            HashAlgorithm::Jump => jump_consistent_hash(key, num_shards),
+            HashAlgorithm::Rendezvous => (0..num_shards)
+                .max_by_key(|&shard| xxh3_64_with_seed(&key.to_le_bytes(), shard as u64))
+                .unwrap_or(0),
+        }
+    }
 }
 
 /// Jump consistent hash algorithm (Lamping & Veach, 2014).
@@ -82,6 +308,50 @@ mod tests {
         );
     }
 
+    #[test]
+    fn resized_keeps_weights_and_adds_unit_shards() {
+        let layout = ShardLayout::weighted(&[2, 1]);
+        for (resized, expected) in [
+            (layout.resized(2), ShardLayout::weighted(&[2, 1])),
+            (layout.resized(3), ShardLayout::weighted(&[2, 1, 1])),
+            (layout.resized(1), ShardLayout::weighted(&[2])),
+        ] {
+            assert_eq!(resized.num_shards(), expected.num_shards());
+            for i in 0..1000 {
+                let key = format!("id=\"{i}\"");
+                assert_eq!(resized.assign("m", &key), expected.assign("m", &key));
+            }
+        }
+    }
+
+    #[test]
+    fn uniform_layout_matches_plain_jump_hash() {
+        let layout = ShardLayout::uniform(7);
+        for i in 0..1000 {
+            let key = format!("id=\"{i}\"");
+            assert_eq!(
+                layout.assign("m", &key),
+                assign_shard_from_parts("m", &key, 7)
+            );
+        }
+    }
+
+    #[test]
+    fn weighted_layout_balance() {
+        // Shard 0 has weight 2, shards 1 and 2 have weight 1: expect ~50/25/25.
+        let layout = ShardLayout::weighted(&[2, 1, 1]);
+        let num_series = 20000;
+        let mut counts = [0u32; 3];
+        for i in 0..num_series {
+            counts[layout.assign("m", &format!("id=\"{i}\"")) as usize] += 1;
+        }
+        let ratio = counts[0] as f64 / ((counts[1] + counts[2]) as f64 / 2.0);
+        assert!(
+            (1.8..2.2).contains(&ratio),
+            "weight-2 shard should get ~2x the series, got {counts:?} (ratio {ratio:.2})"
+        );
+    }
+
     #[test]
     fn reasonable_balance() {
         let num_shards = 4;
@@ -104,4 +374,87 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn seed_changes_assignment_and_zero_seed_matches_unseeded() {
+        let layout = ShardLayout::uniform(8);
+        let seeded = ShardLayout::uniform(8).with_seed(42);
+        let mut differ = 0;
+        for i in 0..1000 {
+            let name = format!("metric_{i}");
+            assert_eq!(
+                layout.assign(&name, "a=\"1\""),
+                assign_shard_from_parts(&name, "a=\"1\"", 8)
+            );
+            if layout.assign(&name, "") != seeded.assign(&name, "") {
+                differ += 1;
+            }
+        }
+        assert!(
+            differ > 500,
+            "seed barely changed assignment: {differ}/1000"
+        );
+    }
+
+    #[test]
+    fn fingerprint_changes_only_with_placement_inputs() {
+        let base = ShardLayout::uniform(4);
+        assert_eq!(base.fingerprint(), ShardLayout::uniform(4).fingerprint());
+        assert_eq!(
+            base.fingerprint(),
+            ShardLayout::weighted(&[1, 1, 1, 1]).fingerprint()
+        );
+        let changed = [
+            ShardLayout::uniform(5),
+            ShardLayout::weighted(&[2, 1, 1, 1]),
+            ShardLayout::uniform(4).with_seed(1),
+            ShardLayout::uniform(4).with_shard_by(ShardBy::Family),
+            ShardLayout::uniform(4).with_pins(vec![(Regex::new("up").unwrap(), 0)]),
+            ShardLayout::uniform(4).with_hash_labels(vec!["job".to_owned()]),
+        ];
+        for layout in changed {
+            assert_ne!(base.fingerprint(), layout.fingerprint(), "{layout:?}");
+        }
+    }
+
+    #[test]
+    fn family_shard_only_set_for_pins_or_family_mode() {
+        let series = ShardLayout::uniform(4);
+        assert_eq!(series.family_shard("up"), None);
+        let family = ShardLayout::uniform(4).with_shard_by(ShardBy::Family);
+        assert_eq!(family.family_shard("up"), Some(family.assign("up", "")));
+    }
+
+    #[test]
+    fn hash_labels_ignore_other_labels() {
+        let layout = ShardLayout::uniform(16)
+            .with_hash_labels(vec!["job".to_owned(), "instance".to_owned()]);
+        let line = |extra: &str| format!(r#"up{{instance="a:9100",{extra},job="node"}} 1"#);
+        let expected = layout.assign("up", r#"instance="a:9100",job="node""#);
+        for i in 0..50 {
+            let line = line(&format!(r#"device="sd{i}""#));
+            let key = crate::parser::extract_sorted_label_key(&line);
+            assert_eq!(layout.assign_line("up", &line, &key), expected);
+        }
+    }
+
+    #[test]
+    fn rendezvous_moves_about_one_in_n_plus_one_on_growth() {
+        let keys: Vec<u64> = (0..10_000)
+            .map(|i| series_hash("m", &format!("id=\"{i}\""), 0))
+            .collect();
+        for algorithm in [HashAlgorithm::Jump, HashAlgorithm::Rendezvous] {
+            let moved = keys
+                .iter()
+                .filter(|&&k| algorithm.shard(k, 4) != algorithm.shard(k, 5))
+                .count();
+            // Ideal is 1/5 of the keys; every mover lands on the new shard.
+            assert!((1700..2300).contains(&moved), "{algorithm:?}: {moved}");
+            assert!(
+                keys.iter()
+                    .filter(|&&k| algorithm.shard(k, 4) != algorithm.shard(k, 5))
+                    .all(|&k| algorithm.shard(k, 5) == 4)
+            );
+        }
+    }
 }
@@ -0,0 +1,85 @@
+use bytes::Bytes;
+use serde_json::{Map, Value, json};
+
+use crate::parser::{
+    extract_metric_name, parse_families, sample_labels, sample_timestamp, sample_value,
+};
+
+/// Encodes a rendered shard text as JSON for consumers that want structure
+/// rather than exposition text:
+///
+/// ```text
+/// [{"name": "http_latency", "help": "...", "type": "histogram",
+///   "samples": [{"name": "http_latency_bucket", "labels": {"le": "0.1"},
+///                "value": 3, "timestamp": 1700000000000}, ...]}, ...]
+/// ```
+///
+/// `help` and `type` are `null` when the family has no such line, and
+/// `timestamp` (milliseconds) is present only on samples that carry one.
+/// Each sample's `name` tells a histogram's `_bucket`, `_sum` and `_count`
+/// apart. Non-finite values are the strings `"NaN"`, `"+Inf"` and `"-Inf"`.
+pub fn encode_shard_json(text: &str) -> Bytes {
+    let families: Vec<Value> = parse_families(text)
+        .0
+        .iter()
+        .map(|family| {
+            let samples: Vec<Value> = family
+                .samples
+                .iter()
+                .map(|sample| {
+                    let line = &sample.raw_line;
+                    let labels: Map<String, Value> = sample_labels(line)
+                        .into_iter()
+                        .map(|(name, value)| (name, Value::String(value)))
+                        .collect();
+                    let mut out = json!({
+                        "name": extract_metric_name(line),
+                        "labels": labels,
+                        "value": json_value(sample_value(line).unwrap_or(f64::NAN)),
+                    });
+                    if let Some(ts) = sample_timestamp(line) {
+                        out["timestamp"] = json!(ts);
+                    }
+                    out
+                })
+                .collect();
+            let mut out = json!({
+                "name": family.name,
+                "help": family.help_line.as_deref().map(|l| comment_text(l, &family.name)),
+                "type": family.type_line.as_deref().map(|l| comment_text(l, &family.name)),
+                "samples": samples,
+            });
+            if let Some(unit) = &family.unit_line {
+                out["unit"] = json!(comment_text(unit, &family.name));
+            }
+            out
+        })
+        .collect();
+    Bytes::from(Value::Array(families).to_string())
+}
+
+/// The text after `# HELP name `, `# TYPE name ` or `# UNIT name `.
+fn comment_text<'a>(line: &'a str, family: &str) -> &'a str {
+    let line = line.trim_end_matches('\n');
+    let rest = line
+        .strip_prefix("# HELP ")
+        .or_else(|| line.strip_prefix("# TYPE "))
+        .or_else(|| line.strip_prefix("# UNIT "))
+        .unwrap_or(line)
+        .trim_start();
+    let rest = rest
+        .strip_prefix(family)
+        .or_else(|| rest.strip_prefix(&format!("\"{family}\"")))
+        .unwrap_or(rest);
+    rest.trim()
+}
+
+fn json_value(v: f64) -> Value {
+    if v.is_nan() {
+        json!("NaN")
+    } else if v.is_infinite() {
+        json!(if v > 0.0 { "+Inf" } else { "-Inf" })
+    } else {
+        json!(v)
+    }
+}
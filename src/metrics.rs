@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use axum::http::StatusCode;
+
+use crate::config::is_valid_label_name;
+use crate::parser::escape_label_value;
+
+/// Upper bounds in seconds of the `prom_reaper_http_request_duration_seconds`
+/// buckets; `+Inf` is implied.
+pub const REQUEST_DURATION_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Request counts and handler latency of one route.
+#[derive(Debug, Clone, Default)]
+pub struct RouteStats {
+    /// Requests by status code.
+    pub codes: BTreeMap<u16, u64>,
+    /// Requests per bucket of [`REQUEST_DURATION_BUCKETS`] (not cumulative),
+    /// with the last slot for `+Inf`.
+    pub buckets: [u64; REQUEST_DURATION_BUCKETS.len() + 1],
+    pub duration_sum: Duration,
+}
+
+/// Process-lifetime self-metrics. Unlike `ShardedState`, which is replaced on
+/// every scrape cycle, these accumulate for as long as the process runs.
+#[derive(Default)]
+pub struct Metrics {
+    /// Responses served, keyed by exact HTTP status code.
+    http_responses: Mutex<BTreeMap<u16, u64>>,
+    /// Requests to matched routes, keyed by route pattern (`/metrics/shard/{id}`).
+    http_requests: Mutex<BTreeMap<String, RouteStats>>,
+    /// The config file on disk no longer matches the one loaded at startup.
+    config_file_changed: AtomicBool,
+}
+
+impl Metrics {
+    pub fn record_response(&self, status: StatusCode) {
+        let mut counts = self.http_responses.lock().unwrap();
+        *counts.entry(status.as_u16()).or_default() += 1;
+    }
+
+    pub fn record_request(&self, route: &str, status: StatusCode, duration: Duration) {
+        let mut routes = self.http_requests.lock().unwrap();
+        let stats = match routes.get_mut(route) {
+            Some(stats) => stats,
+            None => routes.entry(route.to_owned()).or_default(),
+        };
+        *stats.codes.entry(status.as_u16()).or_default() += 1;
+        let secs = duration.as_secs_f64();
+        let bucket = REQUEST_DURATION_BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(REQUEST_DURATION_BUCKETS.len());
+        stats.buckets[bucket] += 1;
+        stats.duration_sum += duration;
+    }
+
+    /// Zeroes every counter. Codes already seen keep reporting, at 0, so
+    /// scrapers observe a counter reset rather than a vanished series.
+    pub fn reset(&self) {
+        let mut counts = self.http_responses.lock().unwrap();
+        counts.values_mut().for_each(|n| *n = 0);
+        let mut routes = self.http_requests.lock().unwrap();
+        for stats in routes.values_mut() {
+            stats.codes.values_mut().for_each(|n| *n = 0);
+            stats.buckets = Default::default();
+            stats.duration_sum = Duration::ZERO;
+        }
+    }
+
+    pub fn set_config_file_changed(&self, changed: bool) {
+        self.config_file_changed.store(changed, Ordering::Relaxed);
+    }
+
+    pub fn config_file_changed(&self) -> bool {
+        self.config_file_changed.load(Ordering::Relaxed)
+    }
+
+    /// Memory currently committed by mimalloc, or `None` when it reports none.
+    pub fn allocated_bytes() -> Option<usize> {
+        let mut commit = 0;
+        let mut ignored = [0usize; 7];
+        let [elapsed, user, system, rss, peak_rss, peak_commit, faults] = &mut ignored;
+        // SAFETY: mi_process_info only writes through the given pointers and
+        // is thread-safe per mimalloc docs.
+        unsafe {
+            libmimalloc_sys::mi_process_info(
+                elapsed,
+                user,
+                system,
+                rss,
+                peak_rss,
+                &mut commit,
+                peak_commit,
+                faults,
+            )
+        };
+        (commit > 0).then_some(commit)
+    }
+
+    /// Snapshot of per-route stats in route order.
+    pub fn http_requests(&self) -> Vec<(String, RouteStats)> {
+        let routes = self.http_requests.lock().unwrap();
+        routes.iter().map(|(r, s)| (r.clone(), s.clone())).collect()
+    }
+
+    /// Snapshot of `(status_code, count)` pairs in ascending code order.
+    pub fn http_responses(&self) -> Vec<(u16, u64)> {
+        let counts = self.http_responses.lock().unwrap();
+        counts.iter().map(|(&code, &n)| (code, n)).collect()
+    }
+}
+
+/// The kind on a self-metric family's `# TYPE` line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Gauge,
+    Counter,
+    Histogram,
+}
+
+impl MetricKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Gauge => "gauge",
+            MetricKind::Counter => "counter",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+/// A sample value as the text format spells it: integers as-is, floats with
+/// `NaN`, `+Inf` and `-Inf` for the special values.
+pub trait SampleValue {
+    fn write_to(&self, out: &mut String);
+}
+
+macro_rules! integer_sample_value {
+    ($($t:ty),*) => {$(
+        impl SampleValue for $t {
+            fn write_to(&self, out: &mut String) {
+                let _ = write!(out, "{self}");
+            }
+        }
+    )*};
+}
+
+integer_sample_value!(u8, u32, u64, usize);
+
+impl SampleValue for f64 {
+    fn write_to(&self, out: &mut String) {
+        if self.is_nan() {
+            out.push_str("NaN");
+        } else if self.is_infinite() {
+            out.push_str(if *self > 0.0 { "+Inf" } else { "-Inf" });
+        } else {
+            let _ = write!(out, "{self}");
+        }
+    }
+}
+
+/// Renders self-metrics in the text exposition format. Label values and HELP
+/// text are escaped here, so no URL, route or label name can break a line.
+///
+/// Each family starts with [`family`](Self::family); its samples follow
+/// through the method matching its kind.
+#[derive(Default)]
+pub struct MetricWriter {
+    out: String,
+    /// Name and kind of the family being written, checked in debug builds.
+    current: Option<(String, MetricKind)>,
+}
+
+impl MetricWriter {
+    /// Writes the `# HELP` and `# TYPE` lines that start a family.
+    pub fn family(&mut self, name: &str, kind: MetricKind, help: &str) {
+        debug_assert!(is_valid_metric_name(name), "bad metric name {name:?}");
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+        let _ = writeln!(self.out, "# TYPE {name} {}", kind.as_str());
+        self.current = Some((name.to_owned(), kind));
+    }
+
+    pub fn gauge(&mut self, name: &str, labels: &[(&str, &str)], value: impl SampleValue) {
+        self.check_family(name, MetricKind::Gauge);
+        self.sample(name, labels, &value);
+    }
+
+    pub fn counter(&mut self, name: &str, labels: &[(&str, &str)], value: impl SampleValue) {
+        self.check_family(name, MetricKind::Counter);
+        self.sample(name, labels, &value);
+    }
+
+    /// Writes one histogram series: `_bucket` lines from the cumulative
+    /// counts, one per upper bound in `les` plus `+Inf`, then `_sum` and
+    /// `_count`. `cumulative` holds one more entry than `les`.
+    pub fn histogram(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        les: &[f64],
+        cumulative: &[u64],
+        sum: f64,
+    ) {
+        self.check_family(name, MetricKind::Histogram);
+        debug_assert_eq!(cumulative.len(), les.len() + 1);
+        let bucket = format!("{name}_bucket");
+        let bounds = les
+            .iter()
+            .map(|le| le.to_string())
+            .chain(["+Inf".to_owned()]);
+        for (le, count) in bounds.zip(cumulative) {
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            self.sample(&bucket, &bucket_labels, count);
+        }
+        self.sample(&format!("{name}_sum"), labels, &sum);
+        let count = cumulative.last().copied().unwrap_or_default();
+        self.sample(&format!("{name}_count"), labels, &count);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    fn check_family(&self, name: &str, kind: MetricKind) {
+        debug_assert!(
+            self.current
+                .as_ref()
+                .is_some_and(|(n, k)| n == name && *k == kind),
+            "{name} written outside its {kind:?} family"
+        );
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: &dyn SampleValue) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                debug_assert!(is_valid_label_name(label), "bad label name {label:?}");
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{label}=\"{}\"", escape_label_value(value));
+            }
+            self.out.push('}');
+        }
+        self.out.push(' ');
+        value.write_to(&mut self.out);
+        self.out.push('\n');
+    }
+}
+
+/// `[a-zA-Z_:][a-zA-Z0-9_:]*`: a label name that may also contain colons.
+fn is_valid_metric_name(name: &str) -> bool {
+    !name.starts_with(|c: char| c.is_ascii_digit()) && is_valid_label_name(&name.replace(':', "_"))
+}
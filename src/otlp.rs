@@ -0,0 +1,146 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::Client;
+use reqwest::header::CONTENT_TYPE;
+use serde_json::{Value, json};
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::config::{OtlpConfig, redact_url};
+use crate::state::{ShardedState, SharedState};
+
+/// Pushes a subset of the self-metrics (shard sizes, source health and scrape
+/// durations) to an OTLP/HTTP collector every `interval_secs`, JSON-encoded.
+/// Nothing is pushed before the first scrape; failed pushes are logged and
+/// retried on the next tick.
+pub async fn run_otlp_exporter(config: OtlpConfig, state: SharedState, client: Client) {
+    let url = metrics_url(&config.endpoint);
+    let mut ticker = time::interval(Duration::from_secs(config.interval_secs));
+    ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let guard = state.load();
+        if guard.shards.is_empty() {
+            continue;
+        }
+        let body = encode_metrics(&guard, SystemTime::now());
+        drop(guard);
+        let timeout = Duration::from_secs(config.interval_secs);
+        match push(&client, &url, &body, timeout).await {
+            Ok(()) => debug!(url = %redact_url(&url), "pushed metrics to OTLP collector"),
+            Err(e) => warn!(
+                url = %redact_url(&url),
+                error = %e,
+                "failed to push metrics to OTLP collector"
+            ),
+        }
+    }
+}
+
+/// Posts one encoded request. The error leaves out the URL, which may carry
+/// credentials.
+pub async fn push(
+    client: &Client,
+    url: &str,
+    body: &Value,
+    timeout: Duration,
+) -> Result<(), reqwest::Error> {
+    client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .timeout(timeout)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map(drop)
+        .map_err(reqwest::Error::without_url)
+}
+
+/// `endpoint` is the collector base URL, as in `OTEL_EXPORTER_OTLP_ENDPOINT`;
+/// a URL already ending in the metrics path is used as is.
+fn metrics_url(endpoint: &str) -> String {
+    if endpoint.ends_with("/v1/metrics") {
+        endpoint.to_owned()
+    } else {
+        format!("{}/v1/metrics", endpoint.trim_end_matches('/'))
+    }
+}
+
+/// Builds an `ExportMetricsServiceRequest` in the OTLP JSON encoding. Metric
+/// names and labels match `/metrics`; every metric is a gauge.
+pub fn encode_metrics(state: &ShardedState, now: SystemTime) -> Value {
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos())
+        .to_string();
+    let point = |attrs: &[(&str, &str)], value: f64| {
+        let attributes: Vec<Value> = attrs
+            .iter()
+            .map(|(k, v)| json!({"key": k, "value": {"stringValue": v}}))
+            .collect();
+        json!({"attributes": attributes, "timeUnixNano": now, "asDouble": value})
+    };
+    let gauge = |name: &str, description: &str, points: Vec<Value>| json!({"name": name, "description": description, "gauge": {"dataPoints": points}});
+    let per_shard = |value: fn(&crate::state::ShardData) -> usize| -> Vec<Value> {
+        state
+            .shards
+            .iter()
+            .enumerate()
+            .map(|(i, s)| point(&[("shard", &i.to_string())], value(s) as f64))
+            .collect()
+    };
+
+    let metrics = vec![
+        gauge(
+            "prom_reaper_last_scrape_age_seconds",
+            "Seconds since the last successful scrape cycle.",
+            vec![point(&[], state.last_scrape.elapsed().as_secs_f64())],
+        ),
+        gauge(
+            "prom_reaper_shard_series",
+            "Number of time series in a shard.",
+            per_shard(|s| s.series_count),
+        ),
+        gauge(
+            "prom_reaper_shard_families",
+            "Number of metric families in a shard.",
+            per_shard(|s| s.families_count),
+        ),
+        gauge(
+            "prom_reaper_shard_size_bytes",
+            "Size of a shard's uncompressed text in bytes.",
+            per_shard(|s| s.text.len()),
+        ),
+        gauge(
+            "prom_reaper_source_up",
+            "Whether the last scrape of a source succeeded (1 = success, 0 = failure).",
+            state
+                .source_status
+                .iter()
+                .map(|s| point(&[("url", &s.url)], if s.success { 1.0 } else { 0.0 }))
+                .collect(),
+        ),
+        gauge(
+            "prom_reaper_source_scrape_duration_seconds",
+            "Duration of the last scrape for a source.",
+            state
+                .source_status
+                .iter()
+                .map(|s| point(&[("url", &s.url)], s.duration.as_secs_f64()))
+                .collect(),
+        ),
+    ];
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": "prom_the_reaper"}}]
+            },
+            "scopeMetrics": [{
+                "scope": {"name": "prom_the_reaper", "version": env!("CARGO_PKG_VERSION")},
+                "metrics": metrics,
+            }]
+        }]
+    })
+}
@@ -1,5 +1,8 @@
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap, HashSet};
 
+use serde::{Deserialize, Serialize};
+
 /// A single parsed sample line, preserving the original text.
 pub struct Sample {
     /// The original verbatim line (including trailing newline).
@@ -14,8 +17,13 @@ pub struct ParsedFamily {
     pub help_line: Option<String>,
     /// Verbatim `# TYPE ...` line with trailing newline, if present.
     pub type_line: Option<String>,
+    /// Verbatim OpenMetrics `# UNIT ...` line with trailing newline, if present.
+    pub unit_line: Option<String>,
     /// Individual sample lines.
     pub samples: Vec<Sample>,
+    /// Set for families from a `help_authority` source: their HELP/TYPE
+    /// override other sources' on merge regardless of order.
+    pub help_authority: bool,
 }
 
 /// Injects extra labels into every sample of the given families.
@@ -23,7 +31,7 @@ pub struct ParsedFamily {
 /// Labels are appended to any existing label set on each sample line (or
 /// inserted as the only labels when the sample has none). Keys are sorted
 /// alphabetically for deterministic output. Label values are escaped per the
-/// Prometheus text format (`\` → `\\`, `"` → `\"`).
+/// Prometheus text format (`\` → `\\`, `"` → `\"`, newline → `\n`).
 ///
 /// Because the labels are written into each `Sample::raw_line`, the existing
 /// hashing pipeline (`extract_sorted_label_key` → `assign_shard_from_parts`)
@@ -53,9 +61,59 @@ pub fn inject_labels(families: &mut [ParsedFamily], extra: &HashMap<String, Stri
     }
 }
 
-/// Escapes a Prometheus label value: `\` → `\\`, `"` → `\"`.
-fn escape_label_value(s: &str) -> String {
-    s.replace('\\', "\\\\").replace('"', "\\\"")
+/// Rewrites every sample so its label pairs appear in sorted order, the
+/// order [`extract_sorted_label_key`] uses, so a series reads the same
+/// whichever order an upstream emitted it in. A quoted metric name stays
+/// first; the value, timestamp and anything after them are kept verbatim.
+pub fn canonicalize_labels(families: &mut [ParsedFamily]) {
+    for family in families.iter_mut() {
+        for sample in family.samples.iter_mut() {
+            if let Some(line) = canonical_line(&sample.raw_line) {
+                sample.raw_line = line;
+            }
+        }
+    }
+}
+
+/// The line with its label pairs sorted, or `None` when they already are.
+fn canonical_line(line: &str) -> Option<String> {
+    let open = line.find('{')?;
+    let close = label_block_end(line, open)?;
+    let mut pairs = split_brace_block(&line[..=close]);
+    let name = quoted_metric_name(line).map(|_| pairs.remove(0));
+    let mut sorted: Vec<&str> = pairs.iter().copied().filter(|p| !p.is_empty()).collect();
+    sorted.sort_unstable();
+    if sorted == pairs {
+        return None;
+    }
+    let labels = name.into_iter().chain(sorted).collect::<Vec<_>>().join(",");
+    Some(format!(
+        "{}{{{}}}{}",
+        &line[..open],
+        labels,
+        &line[close + 1..]
+    ))
+}
+
+/// Index of the `}` closing the label block opened at `open`, skipping
+/// braces inside quoted values, so a trailing exemplar's block is not taken
+/// for it.
+fn label_block_end(line: &str, open: usize) -> Option<usize> {
+    let mut pos = open + 1;
+    loop {
+        let i = pos + line[pos..].find(['"', '}'])?;
+        if line.as_bytes()[i] == b'}' {
+            return Some(i);
+        }
+        pos = i + 1 + closing_quote(&line[i + 1..])? + 1;
+    }
+}
+
+/// Escapes a Prometheus label value: `\` → `\\`, `"` → `\"`, newline → `\n`.
+pub(crate) fn escape_label_value(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
 }
 
 /// Injects a pre-rendered `k="v",...` fragment into a single sample line.
@@ -92,68 +150,247 @@ fn inject_into_line(line: &str, extra_str: &str) -> String {
     }
 }
 
+/// Line counts from one [`parse_families`] call.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseStats {
+    /// Non-blank lines.
+    pub lines: usize,
+    pub samples: usize,
+    /// `#` lines, including HELP and TYPE.
+    pub comments: usize,
+    /// Lines without a valid `name[{labels}] value` shape; skipped.
+    pub malformed: usize,
+    /// Invalid UTF-8 sequences in the body, each replaced with U+FFFD.
+    pub invalid_utf8: usize,
+    /// Samples rejected by strict value checking: a value that isn't a
+    /// float, `±Inf` or `NaN`, or a bad or extra token after it.
+    pub invalid_values: usize,
+}
+
 /// Parses Prometheus exposition format text into metric families.
 ///
 /// Groups HELP, TYPE, and sample lines by metric base name.
 /// Histogram/summary suffixes (_bucket, _count, _sum, _total, _created, _info)
 /// are grouped with their base metric via the TYPE declaration.
-pub fn parse_families(input: &str) -> Vec<ParsedFamily> {
-    let mut families: Vec<ParsedFamily> = Vec::new();
+/// Malformed sample lines are skipped and counted in the returned [`ParseStats`].
+///
+/// Thin wrapper over [`FamilyParser`] for bodies already held in memory.
+pub fn parse_families(input: &str) -> (Vec<ParsedFamily>, ParseStats) {
+    let mut parser = FamilyParser::default();
+    for line in input.lines() {
+        parser.push_line(line);
+    }
+    parser.finish()
+}
+
+/// Incremental form of [`parse_families`]: lines are fed one at a time, so a
+/// response can be parsed as it streams in without buffering the whole body.
+#[derive(Default)]
+pub struct FamilyParser {
+    stats: ParseStats,
+    families: Vec<ParsedFamily>,
     // Index into `families` for the current family being built.
-    let mut current_idx: Option<usize> = None;
+    current_idx: Option<usize>,
     // The TYPE-declared base name (may differ from the sample name due to suffixes).
-    let mut current_base: Option<String> = None;
+    current_base: Option<String>,
+    strict_values: bool,
+}
 
-    for line in input.lines() {
+impl FamilyParser {
+    /// With `strict`, samples are only kept when everything after the labels
+    /// is a value and an optional integer timestamp; others are counted in
+    /// [`ParseStats::invalid_values`] rather than as malformed.
+    pub fn strict_values(mut self, strict: bool) -> Self {
+        self.strict_values = strict;
+        self
+    }
+
+    /// Feeds one line. Any trailing `\r`/`\n` is stripped, so every stored
+    /// HELP, TYPE and sample line ends with exactly one `\n` whatever the
+    /// upstream's line endings, and shards concatenate cleanly.
+    pub fn push_line(&mut self, line: &str) {
+        let line = line.trim_end_matches(['\r', '\n']);
         if line.is_empty() {
-            continue;
+            return;
+        }
+        let stats = &mut self.stats;
+        let families = &mut self.families;
+        stats.lines += 1;
+        if line.starts_with('#') {
+            stats.comments += 1;
         }
 
         if let Some(rest) = line.strip_prefix("# HELP ") {
-            let name = first_token(rest).to_owned();
-            let idx = get_or_insert(&mut families, &name);
+            let name = comment_metric_name(rest).to_owned();
+            let idx = get_or_insert(families, &name);
             families[idx].help_line = Some(format!("{line}\n"));
-            current_base = Some(name.clone());
-            current_idx = Some(idx);
+            self.current_base = Some(name);
+            self.current_idx = Some(idx);
         } else if let Some(rest) = line.strip_prefix("# TYPE ") {
-            let name = first_token(rest).to_owned();
-            let idx = get_or_insert(&mut families, &name);
+            let name = comment_metric_name(rest).to_owned();
+            let idx = get_or_insert(families, &name);
             families[idx].type_line = Some(format!("{line}\n"));
-            current_base = Some(name.clone());
-            current_idx = Some(idx);
+            self.current_base = Some(name);
+            self.current_idx = Some(idx);
+        } else if let Some(rest) = line.strip_prefix("# UNIT ") {
+            let name = comment_metric_name(rest).to_owned();
+            let idx = get_or_insert(families, &name);
+            families[idx].unit_line = Some(format!("{line}\n"));
+            self.current_base = Some(name);
+            self.current_idx = Some(idx);
         } else if line.starts_with('#') {
-            // Non-HELP/TYPE comment — skip
+            // Non-HELP/TYPE/UNIT comment — skip
+        } else if self.strict_values && has_sample_shape(line) && !has_valid_value(line) {
+            stats.invalid_values += 1;
+        } else if !is_well_formed_sample(line) {
+            stats.malformed += 1;
         } else {
             // Sample line
+            stats.samples += 1;
             let sample_name = extract_metric_name(line);
 
             // Determine which family this sample belongs to.
-            let idx = if current_base
+            let idx = if self
+                .current_base
                 .as_deref()
                 .is_some_and(|base| sample_belongs_to(sample_name, base))
             {
                 // Belongs to the current TYPE-declared family.
-                current_idx.unwrap_or_else(|| {
-                    get_or_insert(&mut families, current_base.as_deref().unwrap())
+                self.current_idx.unwrap_or_else(|| {
+                    get_or_insert(families, self.current_base.as_deref().unwrap())
                 })
             } else {
                 // New family encountered without a TYPE declaration.
                 let base = base_name(sample_name);
-                let idx = get_or_insert(&mut families, base);
-                current_base = Some(base.to_owned());
-                current_idx = Some(idx);
+                let idx = get_or_insert(families, base);
+                self.current_base = Some(base.to_owned());
+                self.current_idx = Some(idx);
                 idx
             };
 
             families[idx].samples.push(Sample {
-                raw_line: format!("{line}\n"),
+                raw_line: normalize_sample_line(line, sample_name),
             });
         }
     }
 
-    // Drop families with no samples (e.g. orphaned HELP/TYPE lines).
-    families.retain(|f| !f.samples.is_empty());
-    families
+    /// Returns the parsed families and line counts.
+    pub fn finish(mut self) -> (Vec<ParsedFamily>, ParseStats) {
+        // Drop families with no samples (e.g. orphaned HELP/TYPE lines).
+        self.families.retain(|f| !f.samples.is_empty());
+        (self.families, self.stats)
+    }
+}
+
+/// Splits a byte stream arriving in arbitrary chunks into lines, reusing one
+/// buffer for the partial line carried between chunks.
+///
+/// Lines are decoded like [`decode_lossy`], so invalid UTF-8 becomes U+FFFD
+/// and is counted. A trailing `\r` is stripped like [`str::lines`].
+#[derive(Default)]
+pub struct LineSplitter {
+    partial: Vec<u8>,
+    invalid_utf8: usize,
+}
+
+impl LineSplitter {
+    /// Calls `f` with every line completed by `chunk`.
+    pub fn push(&mut self, mut chunk: &[u8], mut f: impl FnMut(&str)) {
+        while let Some(pos) = chunk.iter().position(|&b| b == b'\n') {
+            let (head, rest) = (&chunk[..pos], &chunk[pos + 1..]);
+            if self.partial.is_empty() {
+                self.invalid_utf8 += emit_line(head, &mut f);
+            } else {
+                self.partial.extend_from_slice(head);
+                self.invalid_utf8 += emit_line(&self.partial, &mut f);
+                self.partial.clear();
+            }
+            chunk = rest;
+        }
+        self.partial.extend_from_slice(chunk);
+    }
+
+    /// Flushes the final line when the stream does not end with a newline.
+    /// Returns the number of invalid UTF-8 sequences replaced in the stream.
+    pub fn finish(mut self, mut f: impl FnMut(&str)) -> usize {
+        if !self.partial.is_empty() {
+            self.invalid_utf8 += emit_line(&self.partial, &mut f);
+        }
+        self.invalid_utf8
+    }
+}
+
+/// Decodes and emits one line, returning its invalid UTF-8 sequence count.
+fn emit_line(line: &[u8], f: &mut impl FnMut(&str)) -> usize {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let (text, invalid) = decode_lossy(line);
+    f(&text);
+    invalid
+}
+
+/// Decodes `bytes` as UTF-8, replacing each invalid sequence with U+FFFD as
+/// `String::from_utf8_lossy` does, and returns how many were replaced.
+pub fn decode_lossy(bytes: &[u8]) -> (Cow<'_, str>, usize) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (Cow::Borrowed(text), 0),
+        Err(_) => {
+            let invalid = bytes
+                .utf8_chunks()
+                .filter(|chunk| !chunk.invalid().is_empty())
+                .count();
+            (String::from_utf8_lossy(bytes), invalid)
+        }
+    }
+}
+
+/// A sample line needs a valid metric name, a closed label block if it opens
+/// one, and a numeric value.
+fn is_well_formed_sample(line: &str) -> bool {
+    has_sample_shape(line) && sample_value(line).is_some()
+}
+
+/// Whether the line starts with a valid metric name and, if it opens one, a
+/// closed label block.
+fn has_sample_shape(line: &str) -> bool {
+    if let Some(name) = quoted_metric_name(line) {
+        return !name.is_empty() && line.contains('}');
+    }
+    let name = extract_metric_name(line);
+    is_valid_metric_name(name) && (!line[name.len()..].starts_with('{') || line.contains('}'))
+}
+
+/// Whether `name` is a metric name in the legacy character set, usable
+/// without quoting: `[a-zA-Z_:][a-zA-Z0-9_:]*`.
+pub(crate) fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Whether the tokens after the labels are exactly a float value (including
+/// `±Inf` and `NaN`) and an optional integer millisecond timestamp.
+fn has_valid_value(line: &str) -> bool {
+    let mut tokens = value_tokens(line);
+    let value_ok = tokens.next().is_some_and(|v| v.parse::<f64>().is_ok());
+    let timestamp_ok = tokens.next().is_none_or(|ts| ts.parse::<i64>().is_ok());
+    value_ok && timestamp_ok && tokens.next().is_none()
+}
+
+/// Renders a sample line with a trailing newline, canonicalising an empty
+/// label block: `foo{} 1` becomes `foo 1`. Both forms share the same hash
+/// key; normalising here keeps the served text canonical regardless of which
+/// form an upstream used.
+fn normalize_sample_line(line: &str, sample_name: &str) -> String {
+    if line.starts_with('{') {
+        // Quoted-name form: the braces always hold at least the name.
+        return format!("{line}\n");
+    }
+    match line[sample_name.len()..].strip_prefix("{}") {
+        Some(rest) => format!("{sample_name}{rest}\n"),
+        None => format!("{line}\n"),
+    }
 }
 
 /// Statistics returned by [`merge_families`].
@@ -162,22 +399,250 @@ pub struct MergeStats {
     pub duplicate_count: usize,
     /// Up to three human-readable examples of dropped series (for warn logging).
     pub examples: Vec<String>,
+    /// Number of families whose `# TYPE` disagreed with the one already kept.
+    pub type_conflicts: usize,
+    /// Up to three examples such as `up (gauge vs counter)`, kept type first.
+    pub type_conflict_examples: Vec<String>,
+    /// Sample lines dropped because their family's type lost a conflict.
+    pub type_conflict_dropped: usize,
+    /// Counter and histogram samples added into an identical series already
+    /// kept, by [`sum_families`].
+    pub summed_count: usize,
+}
+
+/// Which copy of a series exposed by several sources is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupKeep {
+    /// The first source's, in config order.
+    #[default]
+    First,
+    /// The last source's, so later sources override earlier ones.
+    Last,
 }
 
 /// Merges `Vec<ParsedFamily>` collected from multiple sources into a deduplicated list.
 ///
 /// When the same `(family_name, label_key)` appears more than once the **first** occurrence
-/// is kept and all subsequent ones are silently dropped (first-wins).  Families with the
+/// is kept and all subsequent ones are silently dropped (first-wins), or with
+/// [`DedupKeep::Last`] each later one replaces the kept sample in place.  Families with the
 /// same name but distinct label sets are merged into one `ParsedFamily` entry, preserving
-/// their HELP/TYPE from the first source that declared them.
-pub fn merge_families(families: Vec<ParsedFamily>) -> (Vec<ParsedFamily>, MergeStats) {
+/// their HELP/TYPE from the first source that declared them — unless a later family is
+/// marked `help_authority`, in which case its HELP/TYPE replace the earlier ones.
+///
+/// A family whose declared type differs from the kept one is counted in
+/// [`MergeStats::type_conflicts`]. With `drop_type_conflicts` the samples of the
+/// losing family are dropped; otherwise they are served under the kept TYPE.
+pub fn merge_families(
+    families: Vec<ParsedFamily>,
+    keep: DedupKeep,
+    drop_type_conflicts: bool,
+) -> (Vec<ParsedFamily>, MergeStats) {
+    merge(families, Collisions::Dedup(keep), drop_type_conflicts)
+}
+
+/// Like [`merge_families`], but an identical counter or histogram series from
+/// several sources, e.g. replicas each exposing part of the traffic, is
+/// served once with the values added up. The series key includes the sample
+/// name, so a histogram's `_bucket`, `_sum` and `_count` add up separately.
+///
+/// Other families are deduplicated per `keep`, as are pairs whose TYPEs
+/// differ. Pairs whose values aren't finite (`±Inf`, `NaN`) stay first-wins.
+/// The first sample's labels and timestamp are kept.
+pub fn sum_families(
+    families: Vec<ParsedFamily>,
+    keep: DedupKeep,
+    drop_type_conflicts: bool,
+) -> (Vec<ParsedFamily>, MergeStats) {
+    merge(families, Collisions::Sum(keep), drop_type_conflicts)
+}
+
+/// Like [`merge_families`] but keeps every sample: families are still grouped
+/// by name (exposition requires each family to be contiguous), while identical
+/// series from different sources are all served.
+pub fn group_families(
+    families: Vec<ParsedFamily>,
+    drop_type_conflicts: bool,
+) -> (Vec<ParsedFamily>, MergeStats) {
+    merge(families, Collisions::KeepAll, drop_type_conflicts)
+}
+
+/// How [`merge`] treats a series another source already provided.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Collisions {
+    KeepAll,
+    Dedup(DedupKeep),
+    /// Sums counters and histograms, deduplicating everything else.
+    Sum(DedupKeep),
+}
+
+/// Whether values of the same series from different sources can be added.
+fn is_summable(type_name: Option<&str>) -> bool {
+    matches!(type_name, Some("counter" | "histogram"))
+}
+
+/// The sample name and sorted label key of a line, e.g. `h_bucket\0le="1"`.
+fn series_key(line: &str) -> String {
+    format!(
+        "{}\0{}",
+        extract_metric_name(line),
+        extract_sorted_label_key(line)
+    )
+}
+
+/// Renders a [`series_key`] as `name{labels}` for log examples.
+fn series_example(key: &str) -> String {
+    match key.split_once('\0') {
+        Some((name, "")) => name.to_owned(),
+        Some((name, labels)) => format!("{name}{{{labels}}}"),
+        None => key.to_owned(),
+    }
+}
+
+/// Replaces the value of a sample line, keeping its labels and timestamp.
+fn with_sample_value(line: &str, value: f64) -> String {
+    let content = line.strip_suffix('\n').unwrap_or(line);
+    let Some(token) = value_tokens(content).next() else {
+        return line.to_owned();
+    };
+    // `token` borrows from `content`, so its offset locates it.
+    let start = token.as_ptr() as usize - content.as_ptr() as usize;
+    let end = start + token.len();
+    format!("{}{value}{}\n", &content[..start], &content[end..])
+}
+
+/// Returns the metric type declared by a `# TYPE name type` line.
+fn declared_type(type_line: &Option<String>) -> Option<&str> {
+    type_line.as_deref()?.split_whitespace().nth(3)
+}
+
+fn merge(
+    families: Vec<ParsedFamily>,
+    collisions: Collisions,
+    drop_type_conflicts: bool,
+) -> (Vec<ParsedFamily>, MergeStats) {
     let mut merged: Vec<ParsedFamily> = Vec::new();
     let mut name_to_idx: HashMap<String, usize> = HashMap::new();
     let mut duplicate_count = 0usize;
     let mut examples: Vec<String> = Vec::new();
+    let mut type_conflicts = 0usize;
+    let mut type_conflict_examples: Vec<String> = Vec::new();
+    let mut type_conflict_dropped = 0usize;
+    let mut summed_count = 0usize;
 
-    for family in families {
+    for mut family in families {
         if let Some(&idx) = name_to_idx.get(&family.name) {
+            let existing = &mut merged[idx];
+            let authority_takes_over = family.help_authority && !existing.help_authority;
+            if let (Some(kept), Some(other)) = (
+                declared_type(&existing.type_line),
+                declared_type(&family.type_line),
+            ) && kept != other
+            {
+                type_conflicts += 1;
+                let (winner, loser) = if authority_takes_over {
+                    (other, kept)
+                } else {
+                    (kept, other)
+                };
+                if type_conflict_examples.len() < 3 {
+                    type_conflict_examples.push(format!("{} ({winner} vs {loser})", family.name));
+                }
+                if drop_type_conflicts {
+                    let losing = if authority_takes_over {
+                        &mut existing.samples
+                    } else {
+                        &mut family.samples
+                    };
+                    type_conflict_dropped += losing.len();
+                    losing.clear();
+                }
+            }
+            if authority_takes_over {
+                if family.help_line.is_some() {
+                    existing.help_line = family.help_line.clone();
+                }
+                if family.type_line.is_some() {
+                    existing.type_line = family.type_line.clone();
+                }
+                if family.unit_line.is_some() {
+                    existing.unit_line = family.unit_line.clone();
+                }
+                existing.help_authority = true;
+            }
+
+            if collisions == Collisions::KeepAll {
+                merged[idx].samples.extend(family.samples);
+                continue;
+            }
+
+            let keep = match collisions {
+                Collisions::Dedup(keep) | Collisions::Sum(keep) => keep,
+                Collisions::KeepAll => unreachable!(),
+            };
+            let kept_type = declared_type(&merged[idx].type_line);
+            if matches!(collisions, Collisions::Sum(_))
+                && is_summable(kept_type)
+                && kept_type == declared_type(&family.type_line)
+            {
+                let existing = &mut merged[idx].samples;
+                let mut positions: HashMap<String, usize> = existing
+                    .iter()
+                    .enumerate()
+                    .map(|(i, s)| (series_key(&s.raw_line), i))
+                    .collect();
+                for sample in family.samples {
+                    let key = series_key(&sample.raw_line);
+                    let Some(&pos) = positions.get(&key) else {
+                        positions.insert(key, existing.len());
+                        existing.push(sample);
+                        continue;
+                    };
+                    let kept = &mut existing[pos].raw_line;
+                    match (sample_value(kept), sample_value(&sample.raw_line)) {
+                        (Some(a), Some(b)) if a.is_finite() && b.is_finite() => {
+                            *kept = with_sample_value(kept, a + b);
+                            summed_count += 1;
+                        }
+                        _ => {
+                            duplicate_count += 1;
+                            if examples.len() < 3 {
+                                examples.push(series_example(&key));
+                            }
+                        }
+                    }
+                }
+                continue;
+            }
+
+            if keep == DedupKeep::Last {
+                // Keyed by sample name too, so a later `_count` replaces the
+                // kept `_count` rather than the `_sum` sharing its labels.
+                let existing = &mut merged[idx].samples;
+                let mut positions: HashMap<String, usize> = existing
+                    .iter()
+                    .enumerate()
+                    .map(|(i, s)| (series_key(&s.raw_line), i))
+                    .collect();
+                for sample in family.samples {
+                    let key = series_key(&sample.raw_line);
+                    match positions.get(&key) {
+                        Some(&pos) => {
+                            duplicate_count += 1;
+                            if examples.len() < 3 {
+                                examples.push(series_example(&key));
+                            }
+                            existing[pos] = sample;
+                        }
+                        None => {
+                            positions.insert(key, existing.len());
+                            existing.push(sample);
+                        }
+                    }
+                }
+                continue;
+            }
+
             // Family already present — merge samples, first-wins on label_key collisions.
             let existing_keys: HashSet<String> = merged[idx]
                 .samples
@@ -213,6 +678,10 @@ pub fn merge_families(families: Vec<ParsedFamily>) -> (Vec<ParsedFamily>, MergeS
         MergeStats {
             duplicate_count,
             examples,
+            type_conflicts,
+            type_conflict_examples,
+            type_conflict_dropped,
+            summed_count,
         },
     )
 }
@@ -226,7 +695,9 @@ fn get_or_insert(families: &mut Vec<ParsedFamily>, name: &str) -> usize {
         name: name.to_owned(),
         help_line: None,
         type_line: None,
+        unit_line: None,
         samples: Vec::new(),
+        help_authority: false,
     });
     families.len() - 1
 }
@@ -236,8 +707,43 @@ fn first_token(s: &str) -> &str {
     s.split_whitespace().next().unwrap_or("")
 }
 
-/// Extracts the metric name from a sample line (everything before `{` or first space).
+/// The metric name in a `# HELP`/`# TYPE` line after the keyword, unquoting
+/// UTF-8 names such as `"my.metric.name"`.
+fn comment_metric_name(rest: &str) -> &str {
+    rest.strip_prefix('"')
+        .and_then(|quoted| closing_quote(quoted).map(|end| &quoted[..end]))
+        .unwrap_or_else(|| first_token(rest))
+}
+
+/// Byte offset of the first `"` in `s` not escaped by a backslash.
+fn closing_quote(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, ch) in s.char_indices() {
+        match ch {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(i),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// The metric name of a sample in the quoted form `{"my.metric.name",label="x"} 1`,
+/// used for names outside the legacy character set. The name is the first
+/// quoted token inside the braces, not followed by `=`.
+fn quoted_metric_name(line: &str) -> Option<&str> {
+    let rest = line.strip_prefix("{\"")?;
+    let end = closing_quote(rest)?;
+    let after = rest[end + 1..].trim_start();
+    (after.starts_with(',') || after.starts_with('}')).then(|| &rest[..end])
+}
+
+/// Extracts the metric name from a sample line (everything before `{` or first
+/// space, or the leading quoted token inside the braces).
 pub(crate) fn extract_metric_name(line: &str) -> &str {
+    if let Some(name) = quoted_metric_name(line) {
+        return name;
+    }
     let end = line.find(['{', ' ']).unwrap_or(line.len());
     &line[..end]
 }
@@ -273,20 +779,108 @@ fn sample_belongs_to(sample_name: &str, base_name: &str) -> bool {
 /// For `http_requests_total{method="GET",code="200"} 1` returns `code="200",method="GET"`.
 /// For `up 1` (no labels) returns `""`.
 pub(crate) fn extract_sorted_label_key(line: &str) -> String {
+    let mut pairs = split_label_pairs(line);
+    pairs.sort_unstable();
+    pairs.join(",")
+}
+
+/// Like [`extract_sorted_label_key`], keeping only the pairs whose label name
+/// is in `names`.
+pub(crate) fn extract_label_subset_key(line: &str, names: &[String]) -> String {
+    let mut pairs = split_label_pairs(line);
+    pairs.retain(|pair| names.iter().any(|name| name == pair_name(pair)));
+    pairs.sort_unstable();
+    pairs.join(",")
+}
+
+/// The label pairs of a sample line in line order, with values unescaped.
+pub(crate) fn sample_labels(line: &str) -> Vec<(String, String)> {
+    split_label_pairs(line)
+        .into_iter()
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+            Some((name.trim().to_owned(), unescape_label_value(value)))
+        })
+        .collect()
+}
+
+/// Reverses the text format's label-value escapes (`\\`, `\"`, `\n`).
+fn unescape_label_value(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Number of label pairs on a sample line, using the same quote-aware split
+/// as [`extract_sorted_label_key`].
+pub(crate) fn count_labels(line: &str) -> usize {
+    split_label_pairs(line).len()
+}
+
+/// Number of series carrying each label name, across all `families`.
+pub fn label_name_counts(families: &[ParsedFamily]) -> BTreeMap<String, usize> {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for sample in families.iter().flat_map(|f| &f.samples) {
+        for pair in split_label_pairs(&sample.raw_line) {
+            let name = pair_name(pair);
+            if name.is_empty() {
+                continue;
+            }
+            match counts.get_mut(name) {
+                Some(n) => *n += 1,
+                None => {
+                    counts.insert(name.to_owned(), 1);
+                }
+            }
+        }
+    }
+    counts
+}
+
+/// The label name of a `name="value"` pair.
+fn pair_name(pair: &str) -> &str {
+    pair.split_once('=').map_or(pair, |(name, _)| name).trim()
+}
+
+/// Splits the `{...}` block of a sample line into trimmed `name="value"` pairs,
+/// ignoring commas inside quoted values. Returns nothing for lines without labels.
+/// A quoted metric name is not a label and is left out.
+fn split_label_pairs(line: &str) -> Vec<&str> {
+    let mut pairs = split_brace_block(line);
+    if quoted_metric_name(line).is_some() && !pairs.is_empty() {
+        pairs.remove(0);
+    }
+    pairs
+}
+
+/// Splits the `{...}` block of a sample line on commas outside quotes.
+fn split_brace_block(line: &str) -> Vec<&str> {
     let open = match line.find('{') {
         Some(i) => i,
-        None => return String::new(),
+        None => return Vec::new(),
     };
     let close = match line.rfind('}') {
         Some(i) => i,
-        None => return String::new(),
+        None => return Vec::new(),
     };
     if close <= open {
-        return String::new();
+        return Vec::new();
     }
     let labels_str = &line[open + 1..close];
     if labels_str.is_empty() {
-        return String::new();
+        return Vec::new();
     }
 
     // Split on commas that are not inside quotes.
@@ -304,8 +898,202 @@ pub(crate) fn extract_sorted_label_key(line: &str) -> String {
         }
     }
     pairs.push(labels_str[start..].trim());
-    pairs.sort_unstable();
-    pairs.join(",")
+    pairs
+}
+
+/// Drops every sample carrying more than `max` label pairs. Families left
+/// without samples are removed. Returns the number of dropped samples.
+pub fn drop_series_over_label_limit(families: &mut Vec<ParsedFamily>, max: usize) -> usize {
+    let mut dropped = 0;
+    for family in families.iter_mut() {
+        let before = family.samples.len();
+        family.samples.retain(|s| count_labels(&s.raw_line) <= max);
+        dropped += before - family.samples.len();
+    }
+    families.retain(|f| !f.samples.is_empty());
+    dropped
+}
+
+/// Resolves label names repeated within one series, as in `foo{a="1",a="2"} 3`,
+/// which Prometheus rejects along with the whole scrape. Keeps the last
+/// occurrence of each name and rewrites the line, or with `drop` removes the
+/// sample. Families left without samples are removed. Returns the number of
+/// affected samples.
+pub fn dedupe_series_labels(families: &mut Vec<ParsedFamily>, drop: bool) -> usize {
+    let mut affected = 0;
+    for family in families.iter_mut() {
+        family.samples.retain_mut(|sample| {
+            let Some(deduped) = dedupe_line_labels(&sample.raw_line) else {
+                return true;
+            };
+            affected += 1;
+            if !drop {
+                sample.raw_line = deduped;
+            }
+            !drop
+        });
+    }
+    families.retain(|f| !f.samples.is_empty());
+    affected
+}
+
+/// Returns the line with only the last occurrence of each label name, or
+/// `None` when no name is repeated.
+fn dedupe_line_labels(line: &str) -> Option<String> {
+    let pairs = split_label_pairs(line);
+    let is_shadowed = |i: usize| {
+        pairs[i + 1..]
+            .iter()
+            .any(|later| pair_name(later) == pair_name(pairs[i]))
+    };
+    if !(0..pairs.len()).any(is_shadowed) {
+        return None;
+    }
+    let mut kept: Vec<&str> = (0..pairs.len())
+        .filter(|&i| !is_shadowed(i))
+        .map(|i| pairs[i])
+        .collect();
+    if quoted_metric_name(line).is_some() {
+        kept.insert(0, split_brace_block(line)[0]);
+    }
+    // `split_label_pairs` found both braces, so these cannot fail.
+    let open = line.find('{')?;
+    let close = line.rfind('}')?;
+    Some(format!(
+        "{}{{{}}}{}",
+        &line[..open],
+        kept.join(","),
+        &line[close + 1..]
+    ))
+}
+
+/// Keeps the `max` families whose names sort first, preserving their order,
+/// and drops the rest. Returns the dropped families' names and series count.
+pub fn cap_families(families: &mut Vec<ParsedFamily>, max: usize) -> (Vec<String>, usize) {
+    if families.len() <= max {
+        return (Vec::new(), 0);
+    }
+    let mut order: Vec<usize> = (0..families.len()).collect();
+    order.sort_unstable_by(|&a, &b| families[a].name.cmp(&families[b].name));
+    let mut keep = vec![false; families.len()];
+    for &i in &order[..max] {
+        keep[i] = true;
+    }
+    let mut dropped = Vec::new();
+    let mut series = 0;
+    let mut i = 0;
+    families.retain(|f| {
+        let kept = keep[i];
+        i += 1;
+        if !kept {
+            dropped.push(f.name.clone());
+            series += f.samples.len();
+        }
+        kept
+    });
+    dropped.sort_unstable();
+    (dropped, series)
+}
+
+/// Removes every family whose name starts with `prefix`. Returns the number
+/// of dropped samples.
+pub fn drop_families_with_prefix(families: &mut Vec<ParsedFamily>, prefix: &str) -> usize {
+    let mut dropped = 0;
+    families.retain(|f| {
+        let keep = !f.name.starts_with(prefix);
+        if !keep {
+            dropped += f.samples.len();
+        }
+        keep
+    });
+    dropped
+}
+
+/// Whitespace-separated tokens after the name and labels of a sample line:
+/// the value, then the optional timestamp.
+fn value_tokens(content: &str) -> std::str::SplitWhitespace<'_> {
+    // Labels may contain spaces inside quoted values; only look past them.
+    let tail_start = content.rfind('}').map_or(0, |i| i + 1);
+    let mut tokens = content[tail_start..].split_whitespace();
+    // Braceless lines start with the metric name.
+    if tail_start == 0 {
+        tokens.next();
+    }
+    tokens
+}
+
+/// The sample value of a line, accepting `NaN` and `±Inf`.
+pub(crate) fn sample_value(line: &str) -> Option<f64> {
+    let content = line.strip_suffix('\n').unwrap_or(line);
+    value_tokens(content).next()?.parse().ok()
+}
+
+/// The optional trailing timestamp of a sample line, in milliseconds.
+pub(crate) fn sample_timestamp(line: &str) -> Option<i64> {
+    split_timestamp(line).1
+}
+
+/// Splits a sample line into everything up to and including the value, and
+/// the optional trailing timestamp in milliseconds.
+fn split_timestamp(line: &str) -> (&str, Option<i64>) {
+    let content = line.strip_suffix('\n').unwrap_or(line);
+    let mut tokens = value_tokens(content);
+    let (Some(_value), Some(ts), None) = (tokens.next(), tokens.next(), tokens.next()) else {
+        return (content, None);
+    };
+    match ts.parse::<i64>() {
+        Ok(ms) => {
+            let ts_start = content.rfind(ts).unwrap_or(content.len());
+            (content[..ts_start].trim_end(), Some(ms))
+        }
+        Err(_) => (content, None),
+    }
+}
+
+/// Drops every sample whose explicit timestamp is before `cutoff_ms`; samples
+/// without one are kept. Families left without samples are removed. Returns
+/// the number of dropped samples.
+pub fn drop_samples_older_than(families: &mut Vec<ParsedFamily>, cutoff_ms: i64) -> usize {
+    let mut dropped = 0;
+    for family in families.iter_mut() {
+        let before = family.samples.len();
+        family
+            .samples
+            .retain(|s| sample_timestamp(&s.raw_line).is_none_or(|ms| ms >= cutoff_ms));
+        dropped += before - family.samples.len();
+    }
+    families.retain(|f| !f.samples.is_empty());
+    dropped
+}
+
+/// Strips (or, with `drop`, removes the sample of) every timestamp outside
+/// `[now_ms - tolerance_ms, now_ms + tolerance_ms]`. Families left without
+/// samples are removed. Returns the number of affected samples.
+pub fn enforce_timestamp_tolerance(
+    families: &mut Vec<ParsedFamily>,
+    now_ms: i64,
+    tolerance_ms: u64,
+    drop: bool,
+) -> usize {
+    let in_range = |ms: i64| now_ms.abs_diff(ms) <= tolerance_ms;
+    let mut affected = 0;
+    for family in families.iter_mut() {
+        family.samples.retain_mut(|sample| {
+            let (without_ts, ts) = split_timestamp(&sample.raw_line);
+            match ts {
+                Some(ms) if !in_range(ms) => {
+                    affected += 1;
+                    if !drop {
+                        sample.raw_line = format!("{without_ts}\n");
+                    }
+                    !drop
+                }
+                _ => true,
+            }
+        });
+    }
+    families.retain(|f| !f.samples.is_empty());
+    affected
 }
 
 #[cfg(test)]
@@ -315,7 +1103,7 @@ mod tests {
     #[test]
     fn simple_gauge() {
         let input = "# HELP up Whether the target is up.\n# TYPE up gauge\nup 1\n";
-        let families = parse_families(input);
+        let families = parse_families(input).0;
         assert_eq!(families.len(), 1);
         assert_eq!(families[0].name, "up");
         assert!(families[0].help_line.is_some());
@@ -330,13 +1118,83 @@ mod tests {
     #[test]
     fn label_key_is_sorted() {
         let input = "# TYPE req counter\nreq{z=\"1\",a=\"2\",m=\"3\"} 1\n";
-        let families = parse_families(input);
+        let families = parse_families(input).0;
         assert_eq!(
             extract_sorted_label_key(&families[0].samples[0].raw_line),
             r#"a="2",m="3",z="1""#
         );
     }
 
+    #[test]
+    fn quoted_metric_name_with_labels() {
+        let input = "# HELP \"my.metric.name\" Dotted.\n# TYPE \"my.metric.name\" gauge\n\
+                     {\"my.metric.name\",label=\"x\",a=\"1\"} 1\n\
+                     {\"my.metric.name\",label=\"y\"} 2\n";
+        let (families, stats) = parse_families(input);
+        assert_eq!(stats.malformed, 0);
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].name, "my.metric.name");
+        assert!(families[0].type_line.is_some());
+        assert_eq!(families[0].samples.len(), 2);
+        let line = &families[0].samples[0].raw_line;
+        assert_eq!(extract_metric_name(line), "my.metric.name");
+        assert_eq!(extract_sorted_label_key(line), r#"a="1",label="x""#);
+        assert_eq!(count_labels(line), 2);
+    }
+
+    #[test]
+    fn quoted_metric_name_without_labels() {
+        let input = "{\"my.metric\"} 1\n{\"my.metric\"} 2\n{\"other \\\"q\\\" m\"} 3\n";
+        let (families, stats) = parse_families(input);
+        assert_eq!(stats.malformed, 0);
+        assert_eq!(families.len(), 2);
+        assert_eq!(families[0].name, "my.metric");
+        assert_eq!(families[0].samples.len(), 2);
+        assert_eq!(families[0].samples[0].raw_line, "{\"my.metric\"} 1\n");
+        assert_eq!(
+            extract_sorted_label_key(&families[0].samples[0].raw_line),
+            ""
+        );
+        assert_eq!(families[1].name, r#"other \"q\" m"#);
+    }
+
+    #[test]
+    fn quoted_metric_name_suffixes_group_under_type() {
+        let input = "# TYPE \"http.latency\" histogram\n\
+                     {\"http.latency_bucket\",le=\"1\"} 3\n\
+                     {\"http.latency_count\"} 3\n";
+        let families = parse_families(input).0;
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].name, "http.latency");
+        assert_eq!(families[0].samples.len(), 2);
+    }
+
+    #[test]
+    fn quoted_label_name_is_not_a_metric_name() {
+        // A bare `{...}` block whose first token is a quoted label name has no metric name.
+        let (families, stats) = parse_families("{\"a.b\"=\"x\"} 1\n");
+        assert!(families.is_empty());
+        assert_eq!(stats.malformed, 1);
+    }
+
+    #[test]
+    fn quoted_metric_name_survives_label_injection_and_dedupe() {
+        let mut families = parse_families("{\"my.metric\",a=\"1\",a=\"2\"} 1\n").0;
+        assert_eq!(dedupe_series_labels(&mut families, false), 1);
+        assert_eq!(
+            families[0].samples[0].raw_line,
+            "{\"my.metric\",a=\"2\"} 1\n"
+        );
+        inject_labels(
+            &mut families,
+            &HashMap::from([("env".to_string(), "prod".to_string())]),
+        );
+        let line = &families[0].samples[0].raw_line;
+        assert_eq!(line, "{\"my.metric\",a=\"2\",env=\"prod\"} 1\n");
+        assert_eq!(extract_metric_name(line), "my.metric");
+        assert_eq!(extract_sorted_label_key(line), r#"a="2",env="prod""#);
+    }
+
     #[test]
     fn histogram_grouped_together() {
         let input = r#"# HELP http_req_duration_seconds A histogram.
@@ -346,16 +1204,42 @@ http_req_duration_seconds_bucket{le="+Inf"} 200
 http_req_duration_seconds_sum 12.3
 http_req_duration_seconds_count 200
 "#;
-        let families = parse_families(input);
+        let families = parse_families(input).0;
         assert_eq!(families.len(), 1);
         assert_eq!(families[0].name, "http_req_duration_seconds");
         assert_eq!(families[0].samples.len(), 4);
     }
 
+    #[test]
+    fn unit_line_is_captured_with_its_family() {
+        let input = "# HELP req_seconds Latency.\n\
+                     # TYPE req_seconds summary\n\
+                     # UNIT req_seconds seconds\n\
+                     req_seconds_sum 3.5\n\
+                     req_seconds_count 7\n\
+                     # UNIT \"dotted.bytes\" bytes\n\
+                     {\"dotted.bytes\"} 1\n\
+                     other 1\n";
+        let (families, stats) = parse_families(input);
+        assert_eq!(stats.comments, 4);
+        assert_eq!(families.len(), 3);
+        assert_eq!(
+            families[0].unit_line.as_deref(),
+            Some("# UNIT req_seconds seconds\n")
+        );
+        assert_eq!(families[0].samples.len(), 2);
+        assert_eq!(families[1].name, "dotted.bytes");
+        assert_eq!(
+            families[1].unit_line.as_deref(),
+            Some("# UNIT \"dotted.bytes\" bytes\n")
+        );
+        assert!(families[2].unit_line.is_none());
+    }
+
     #[test]
     fn multiple_families() {
         let input = "# TYPE cpu counter\ncpu_total{cpu=\"0\"} 100\ncpu_total{cpu=\"1\"} 200\n# TYPE mem gauge\nmem 1024\n";
-        let families = parse_families(input);
+        let families = parse_families(input).0;
         assert_eq!(families.len(), 2);
         assert_eq!(families[0].samples.len(), 2);
         assert_eq!(families[1].samples.len(), 1);
@@ -364,7 +1248,7 @@ http_req_duration_seconds_count 200
     #[test]
     fn no_help_or_type() {
         let input = "my_metric{label=\"a\"} 42\nmy_metric{label=\"b\"} 99\n";
-        let families = parse_families(input);
+        let families = parse_families(input).0;
         assert_eq!(families.len(), 1);
         assert_eq!(families[0].name, "my_metric");
         assert_eq!(families[0].samples.len(), 2);
@@ -375,14 +1259,14 @@ http_req_duration_seconds_count 200
     #[test]
     fn skips_comments_and_blank_lines() {
         let input = "# Some random comment\n\n# HELP foo A foo.\n# TYPE foo gauge\nfoo 1\n\n";
-        let families = parse_families(input);
+        let families = parse_families(input).0;
         assert_eq!(families.len(), 1);
     }
 
     #[test]
     fn untyped_metrics_different_names_split() {
         let input = "aaa 1\nbbb 2\nccc 3\n";
-        let families = parse_families(input);
+        let families = parse_families(input).0;
         assert_eq!(families.len(), 3);
     }
 
@@ -390,7 +1274,7 @@ http_req_duration_seconds_count 200
     fn label_key_comma_in_value() {
         // A label value containing a comma should not be split.
         let input = "req{path=\"/a,b\",method=\"GET\"} 1\n";
-        let families = parse_families(input);
+        let families = parse_families(input).0;
         assert_eq!(
             extract_sorted_label_key(&families[0].samples[0].raw_line),
             r#"method="GET",path="/a,b""#
@@ -404,8 +1288,8 @@ http_req_duration_seconds_count 200
     #[test]
     fn merge_families_no_overlap_is_passthrough() {
         let input = "# TYPE aaa gauge\naaa 1\n# TYPE bbb gauge\nbbb 2\n";
-        let families = parse_families(input);
-        let (merged, stats) = merge_families(families);
+        let families = parse_families(input).0;
+        let (merged, stats) = merge_families(families, DedupKeep::First, false);
         assert_eq!(merged.len(), 2);
         assert_eq!(stats.duplicate_count, 0);
         assert!(stats.examples.is_empty());
@@ -414,9 +1298,9 @@ http_req_duration_seconds_count 200
     #[test]
     fn merge_families_identical_label_key_first_wins() {
         // Two sources expose the same label-less metric.
-        let mut families = parse_families("# TYPE up gauge\nup 1\n");
-        families.extend(parse_families("# TYPE up gauge\nup 0\n"));
-        let (merged, stats) = merge_families(families);
+        let mut families = parse_families("# TYPE up gauge\nup 1\n").0;
+        families.extend(parse_families("# TYPE up gauge\nup 0\n").0);
+        let (merged, stats) = merge_families(families, DedupKeep::First, false);
         assert_eq!(merged.len(), 1);
         assert_eq!(merged[0].samples.len(), 1, "duplicate must be dropped");
         // First value (1) must be kept.
@@ -425,12 +1309,82 @@ http_req_duration_seconds_count 200
         assert_eq!(stats.examples, vec!["up"]);
     }
 
+    #[test]
+    fn sum_families_adds_counters_and_histograms_but_not_gauges() {
+        let replica = |count: u32, inflight: u32| {
+            parse_families(&format!(
+                "# TYPE req_total counter\n\
+                 req_total{{code=\"200\"}} {count}\n\
+                 # TYPE lat histogram\n\
+                 lat_bucket{{le=\"0.5\"}} {count}\n\
+                 lat_bucket{{le=\"+Inf\"}} {count}\n\
+                 lat_sum 1.25\n\
+                 lat_count {count} 1700000000000\n\
+                 # TYPE inflight gauge\n\
+                 inflight {inflight}\n"
+            ))
+            .0
+        };
+        let mut families = replica(10, 3);
+        families.extend(replica(5, 7));
+        let (merged, stats) = sum_families(families, DedupKeep::First, false);
+
+        let lines: Vec<&str> = merged
+            .iter()
+            .flat_map(|f| &f.samples)
+            .map(|s| s.raw_line.as_str())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "req_total{code=\"200\"} 15\n",
+                "lat_bucket{le=\"0.5\"} 15\n",
+                "lat_bucket{le=\"+Inf\"} 15\n",
+                "lat_sum 2.5\n",
+                "lat_count 15 1700000000000\n",
+                "inflight 3\n",
+            ]
+        );
+        assert_eq!(stats.summed_count, 5);
+        assert_eq!(stats.duplicate_count, 1);
+        assert_eq!(stats.examples, ["inflight"]);
+    }
+
+    #[test]
+    fn sum_families_keeps_first_for_non_finite_values() {
+        let mut families = parse_families("# TYPE c counter\nc{a=\"1\"} NaN\nc{a=\"2\"} 4\n").0;
+        families.extend(parse_families("# TYPE c counter\nc{a=\"1\"} 2\nc{a=\"2\"} +Inf\n").0);
+        let (merged, stats) = sum_families(families, DedupKeep::First, false);
+        let lines: Vec<&str> = merged[0]
+            .samples
+            .iter()
+            .map(|s| s.raw_line.as_str())
+            .collect();
+        assert_eq!(lines, ["c{a=\"1\"} NaN\n", "c{a=\"2\"} 4\n"]);
+        assert_eq!(stats.summed_count, 0);
+        assert_eq!(stats.duplicate_count, 2);
+        assert_eq!(stats.examples, ["c{a=\"1\"}", "c{a=\"2\"}"]);
+    }
+
+    #[test]
+    fn merge_families_identical_label_key_last_wins() {
+        let mut families = parse_families("# TYPE up gauge\nup 1\nup_other 5\n").0;
+        families.extend(parse_families("# TYPE up gauge\nup 0\n").0);
+        let (merged, stats) = merge_families(families, DedupKeep::Last, false);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].samples.len(), 1, "duplicate must be dropped");
+        // Second value (0) must be kept.
+        assert_eq!(merged[0].samples[0].raw_line, "up 0\n");
+        assert_eq!(stats.duplicate_count, 1);
+        assert_eq!(stats.examples, vec!["up"]);
+    }
+
     #[test]
     fn merge_families_distinct_label_sets_both_kept() {
         // Same family name, different labels — no collision.
-        let mut families = parse_families("cpu{cpu=\"0\"} 100\n");
-        families.extend(parse_families("cpu{cpu=\"1\"} 200\n"));
-        let (merged, stats) = merge_families(families);
+        let mut families = parse_families("cpu{cpu=\"0\"} 100\n").0;
+        families.extend(parse_families("cpu{cpu=\"1\"} 200\n").0);
+        let (merged, stats) = merge_families(families, DedupKeep::First, false);
         assert_eq!(merged.len(), 1);
         assert_eq!(merged[0].samples.len(), 2);
         assert_eq!(stats.duplicate_count, 0);
@@ -440,9 +1394,9 @@ http_req_duration_seconds_count 200
     fn merge_families_partial_overlap() {
         // Source 1: cpu{cpu="0"} and cpu{cpu="1"}
         // Source 2: cpu{cpu="1"} (duplicate) and cpu{cpu="2"} (new)
-        let mut families = parse_families("cpu{cpu=\"0\"} 10\ncpu{cpu=\"1\"} 20\n");
-        families.extend(parse_families("cpu{cpu=\"1\"} 99\ncpu{cpu=\"2\"} 30\n"));
-        let (merged, stats) = merge_families(families);
+        let mut families = parse_families("cpu{cpu=\"0\"} 10\ncpu{cpu=\"1\"} 20\n").0;
+        families.extend(parse_families("cpu{cpu=\"1\"} 99\ncpu{cpu=\"2\"} 30\n").0);
+        let (merged, stats) = merge_families(families, DedupKeep::First, false);
         assert_eq!(merged.len(), 1);
         assert_eq!(merged[0].samples.len(), 3, "0, 1 and 2 should be present");
         assert_eq!(stats.duplicate_count, 1);
@@ -471,14 +1425,14 @@ http_req_duration_seconds_count 200
 
     #[test]
     fn inject_labels_into_metric_without_labels() {
-        let mut families = parse_families("up 1\n");
+        let mut families = parse_families("up 1\n").0;
         inject_labels(&mut families, &labels(&[("cluster", "prod")]));
         assert_eq!(families[0].samples[0].raw_line, "up{cluster=\"prod\"} 1\n");
     }
 
     #[test]
     fn inject_labels_into_metric_with_existing_labels() {
-        let mut families = parse_families("req{method=\"GET\"} 42\n");
+        let mut families = parse_families("req{method=\"GET\"} 42\n").0;
         inject_labels(&mut families, &labels(&[("cluster", "prod")]));
         assert_eq!(
             families[0].samples[0].raw_line,
@@ -488,7 +1442,7 @@ http_req_duration_seconds_count 200
 
     #[test]
     fn inject_labels_preserves_timestamp() {
-        let mut families = parse_families("up 1 1700000000\n");
+        let mut families = parse_families("up 1 1700000000\n").0;
         inject_labels(&mut families, &labels(&[("dc", "eu")]));
         assert_eq!(
             families[0].samples[0].raw_line,
@@ -498,7 +1452,7 @@ http_req_duration_seconds_count 200
 
     #[test]
     fn inject_labels_multiple_sorted_alphabetically() {
-        let mut families = parse_families("up 1\n");
+        let mut families = parse_families("up 1\n").0;
         inject_labels(
             &mut families,
             &labels(&[("zone", "a"), ("cluster", "prod")]),
@@ -512,7 +1466,7 @@ http_req_duration_seconds_count 200
 
     #[test]
     fn inject_labels_escapes_special_chars_in_value() {
-        let mut families = parse_families("up 1\n");
+        let mut families = parse_families("up 1\n").0;
         inject_labels(&mut families, &labels(&[("label", "val\\with\"quotes")]));
         assert_eq!(
             families[0].samples[0].raw_line,
@@ -523,7 +1477,7 @@ http_req_duration_seconds_count 200
     #[test]
     fn inject_labels_empty_extra_is_noop() {
         let input = "up 1\n";
-        let mut families = parse_families(input);
+        let mut families = parse_families(input).0;
         inject_labels(&mut families, &HashMap::new());
         assert_eq!(families[0].samples[0].raw_line, "up 1\n");
     }
@@ -531,12 +1485,197 @@ http_req_duration_seconds_count 200
     #[test]
     fn inject_labels_affects_shard_key() {
         // With extra labels, extract_sorted_label_key must return a non-empty key.
-        let mut families = parse_families("up 1\n");
+        let mut families = parse_families("up 1\n").0;
         inject_labels(&mut families, &labels(&[("cluster", "prod")]));
         let key = extract_sorted_label_key(&families[0].samples[0].raw_line);
         assert_eq!(key, r#"cluster="prod""#);
     }
 
+    #[test]
+    fn empty_label_block_is_normalized() {
+        let families = parse_families("foo{} 1 1700000000\n").0;
+        assert_eq!(families[0].samples[0].raw_line, "foo 1 1700000000\n");
+    }
+
+    #[test]
+    fn merge_families_empty_label_block_dedupes_with_bare_name() {
+        let mut families = parse_families("# TYPE foo gauge\nfoo{} 1\n").0;
+        families.extend(parse_families("# TYPE foo gauge\nfoo 2\n").0);
+        let (merged, stats) = merge_families(families, DedupKeep::First, false);
+        assert_eq!(merged[0].samples.len(), 1);
+        assert_eq!(merged[0].samples[0].raw_line, "foo 1\n");
+        assert_eq!(stats.duplicate_count, 1);
+    }
+
+    #[test]
+    fn count_labels_respects_quoted_commas() {
+        assert_eq!(count_labels("up 1"), 0);
+        assert_eq!(count_labels("up{} 1"), 0);
+        assert_eq!(count_labels(r#"req{path="/a,b",method="GET"} 1"#), 2);
+    }
+
+    #[test]
+    fn drop_series_over_label_limit_drops_deep_series() {
+        let deep: Vec<String> = (0..20).map(|i| format!("l{i}=\"v\"")).collect();
+        let input = format!(
+            "# TYPE deep gauge\ndeep{{{}}} 1\n# TYPE shallow gauge\nshallow{{a=\"1\"}} 1\n",
+            deep.join(",")
+        );
+        let mut families = parse_families(&input).0;
+        let dropped = drop_series_over_label_limit(&mut families, 10);
+        assert_eq!(dropped, 1);
+        assert_eq!(families.len(), 1, "emptied family must be removed");
+        assert_eq!(families[0].name, "shallow");
+    }
+
+    #[test]
+    fn merge_families_help_authority_wins_when_merged_second() {
+        let mut families =
+            parse_families("# HELP osd_up up.\n# TYPE osd_up gauge\nosd_up{id=\"0\"} 1\n").0;
+        let mut rich = parse_families(
+            "# HELP osd_up Whether the OSD daemon is up (1) or down (0).\n# TYPE osd_up gauge\nosd_up{id=\"1\"} 1\n",
+        ).0;
+        rich.iter_mut().for_each(|f| f.help_authority = true);
+        families.extend(rich);
+
+        let (merged, _) = merge_families(families, DedupKeep::First, false);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(
+            merged[0].help_line.as_deref(),
+            Some("# HELP osd_up Whether the OSD daemon is up (1) or down (0).\n")
+        );
+        assert_eq!(merged[0].samples.len(), 2);
+    }
+
+    #[test]
+    fn merge_families_non_authoritative_second_keeps_first_help() {
+        let mut families = parse_families("# HELP up First.\nup{a=\"1\"} 1\n").0;
+        families.extend(parse_families("# HELP up Second.\nup{a=\"2\"} 1\n").0);
+        let (merged, _) = merge_families(families, DedupKeep::First, false);
+        assert_eq!(merged[0].help_line.as_deref(), Some("# HELP up First.\n"));
+    }
+
+    #[test]
+    fn merge_families_matching_types_are_not_conflicts() {
+        let mut families = parse_families("# TYPE up gauge\nup{a=\"1\"} 1\n").0;
+        families.extend(parse_families("# TYPE up gauge\nup{a=\"2\"} 1\n").0);
+        families.extend(parse_families("up{a=\"3\"} 1\n").0);
+        let (merged, stats) = merge_families(families, DedupKeep::First, true);
+        assert_eq!(merged[0].samples.len(), 3);
+        assert_eq!(stats.type_conflicts, 0);
+        assert!(stats.type_conflict_examples.is_empty());
+    }
+
+    #[test]
+    fn merge_families_type_conflict_counted_and_kept_by_default() {
+        let mut families = parse_families("# TYPE reqs counter\nreqs{a=\"1\"} 1\n").0;
+        families.extend(parse_families("# TYPE reqs gauge\nreqs{a=\"2\"} 5\n").0);
+        let (merged, stats) = merge_families(families, DedupKeep::First, false);
+        assert_eq!(
+            merged[0].type_line.as_deref(),
+            Some("# TYPE reqs counter\n")
+        );
+        assert_eq!(merged[0].samples.len(), 2);
+        assert_eq!(stats.type_conflicts, 1);
+        assert_eq!(
+            stats.type_conflict_examples,
+            vec!["reqs (counter vs gauge)"]
+        );
+        assert_eq!(stats.type_conflict_dropped, 0);
+    }
+
+    #[test]
+    fn merge_families_type_conflict_drop_removes_later_family() {
+        let mut families = parse_families("# TYPE reqs counter\nreqs{a=\"1\"} 1\n").0;
+        families.extend(parse_families("# TYPE reqs gauge\nreqs{a=\"2\"} 5\nreqs{a=\"3\"} 6\n").0);
+        let (merged, stats) = merge_families(families, DedupKeep::First, true);
+        assert_eq!(merged[0].samples.len(), 1);
+        assert!(merged[0].samples[0].raw_line.starts_with("reqs{a=\"1\"}"));
+        assert_eq!(stats.type_conflicts, 1);
+        assert_eq!(stats.type_conflict_dropped, 2);
+    }
+
+    #[test]
+    fn type_conflict_drop_removes_earlier_family_when_authority_wins() {
+        let mut families = parse_families("# TYPE reqs gauge\nreqs{a=\"1\"} 1\n").0;
+        let mut rich = parse_families("# TYPE reqs counter\nreqs{a=\"2\"} 5\n").0;
+        rich.iter_mut().for_each(|f| f.help_authority = true);
+        families.extend(rich);
+        let (merged, stats) = group_families(families, true);
+        assert_eq!(
+            merged[0].type_line.as_deref(),
+            Some("# TYPE reqs counter\n")
+        );
+        assert_eq!(merged[0].samples.len(), 1);
+        assert!(merged[0].samples[0].raw_line.starts_with("reqs{a=\"2\"}"));
+        assert_eq!(
+            stats.type_conflict_examples,
+            vec!["reqs (counter vs gauge)"]
+        );
+    }
+
+    #[test]
+    fn group_families_keeps_identical_series() {
+        // Two sources expose the same series.
+        let mut families = parse_families("# TYPE up gauge\nup{job=\"a\"} 1\nother 1\n").0;
+        families.extend(parse_families("# TYPE up gauge\nup{job=\"a\"} 0\n").0);
+        let grouped = group_families(families, false).0;
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[0].name, "up");
+        assert_eq!(grouped[0].samples.len(), 2);
+    }
+
+    #[test]
+    fn dedupe_series_labels_keeps_last_occurrence() {
+        let input = "foo{a=\"1\",b=\"x\",a=\"2\"} 3\nfoo{a=\"5\"} 4\n";
+        let mut families = parse_families(input).0;
+        assert_eq!(
+            extract_sorted_label_key(&families[0].samples[0].raw_line),
+            r#"a="1",a="2",b="x""#
+        );
+
+        assert_eq!(dedupe_series_labels(&mut families, false), 1);
+        assert_eq!(families[0].samples[0].raw_line, "foo{b=\"x\",a=\"2\"} 3\n");
+        assert_eq!(
+            extract_sorted_label_key(&families[0].samples[0].raw_line),
+            r#"a="2",b="x""#
+        );
+        assert_eq!(families[0].samples[1].raw_line, "foo{a=\"5\"} 4\n");
+    }
+
+    #[test]
+    fn dedupe_series_labels_ignores_names_inside_values() {
+        let input = "foo{a=\"a=1,a=2\",b=\"2\"} 3 1700000000000\n";
+        let mut families = parse_families(input).0;
+        assert_eq!(dedupe_series_labels(&mut families, false), 0);
+        assert_eq!(families[0].samples[0].raw_line, input);
+    }
+
+    #[test]
+    fn dedupe_series_labels_drop_removes_series() {
+        let input = "foo{a=\"1\",a=\"2\"} 3\nfoo{a=\"3\"} 4\nbar{x=\"1\", x=\"1\"} 1\n";
+        let mut families = parse_families(input).0;
+        assert_eq!(dedupe_series_labels(&mut families, true), 2);
+        assert_eq!(families.len(), 1, "emptied family must be removed");
+        assert_eq!(families[0].samples.len(), 1);
+        assert_eq!(families[0].samples[0].raw_line, "foo{a=\"3\"} 4\n");
+    }
+
+    #[test]
+    fn label_name_counts_counts_series_per_name() {
+        let families =
+            parse_families("a{x=\"1\",y=\"a,b\"} 1\na{x=\"2\"} 1\nb{z=\"1\",} 1\nc 1\n").0;
+        let counts = label_name_counts(&families);
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("x".to_string(), 2),
+                ("y".to_string(), 1),
+                ("z".to_string(), 1)
+            ]
+        );
+    }
+
     #[test]
     fn merge_families_examples_capped_at_three() {
         // Four duplicate series — examples list must not exceed 3.
@@ -546,10 +1685,258 @@ http_req_duration_seconds_count 200
             f1_input.push_str(&format!("m{{id=\"{i}\"}} 1\n"));
             f2_input.push_str(&format!("m{{id=\"{i}\"}} 2\n"));
         }
-        let mut families = parse_families(&f1_input);
-        families.extend(parse_families(&f2_input));
-        let (_, stats) = merge_families(families);
+        let mut families = parse_families(&f1_input).0;
+        families.extend(parse_families(&f2_input).0);
+        let (_, stats) = merge_families(families, DedupKeep::First, false);
         assert_eq!(stats.duplicate_count, 4);
         assert_eq!(stats.examples.len(), 3, "examples must be capped at 3");
     }
+
+    #[test]
+    fn split_timestamp_handles_labels_and_bare_lines() {
+        assert_eq!(
+            split_timestamp("up 1 1700000000000\n"),
+            ("up 1", Some(1_700_000_000_000))
+        );
+        assert_eq!(split_timestamp("up 1\n"), ("up 1", None));
+        assert_eq!(
+            split_timestamp("m{path=\"/a b\"} 2.5 -5\n"),
+            ("m{path=\"/a b\"} 2.5", Some(-5))
+        );
+        assert_eq!(split_timestamp("m{a=\"1\"} 3\n"), ("m{a=\"1\"} 3", None));
+    }
+
+    #[test]
+    fn timestamp_tolerance_strips_or_drops_out_of_range() {
+        let input = "m{a=\"1\"} 1 1000\nm{a=\"2\"} 2 999999\nm{a=\"3\"} 3\n";
+
+        let mut families = parse_families(input).0;
+        assert_eq!(
+            enforce_timestamp_tolerance(&mut families, 1000, 60, false),
+            1
+        );
+        let lines: Vec<_> = families[0]
+            .samples
+            .iter()
+            .map(|s| s.raw_line.as_str())
+            .collect();
+        assert_eq!(
+            lines,
+            ["m{a=\"1\"} 1 1000\n", "m{a=\"2\"} 2\n", "m{a=\"3\"} 3\n"]
+        );
+
+        let mut families = parse_families(input).0;
+        assert_eq!(
+            enforce_timestamp_tolerance(&mut families, 1000, 60, true),
+            1
+        );
+        assert_eq!(families[0].samples.len(), 2);
+        assert!(
+            families[0]
+                .samples
+                .iter()
+                .all(|s| !s.raw_line.contains("999999"))
+        );
+    }
+
+    #[test]
+    fn timestamp_tolerance_handles_extreme_timestamps() {
+        let input = format!("m{{a=\"1\"}} 1 {}\nm{{a=\"2\"}} 2 {}\n", i64::MIN, i64::MAX);
+
+        let mut families = parse_families(&input).0;
+        assert_eq!(
+            enforce_timestamp_tolerance(&mut families, i64::MAX, 60, false),
+            1
+        );
+        let mut families = parse_families(&input).0;
+        assert_eq!(
+            enforce_timestamp_tolerance(&mut families, i64::MIN, u64::MAX, false),
+            0
+        );
+    }
+
+    #[test]
+    fn strict_values_reject_invalid_value_tokens() {
+        let input = "m{k=\"int\"} 42\n\
+                     m{k=\"float\"} -0.25\n\
+                     m{k=\"sci\"} 1.5e-3\n\
+                     m{k=\"sci_upper\"} 6.02E23 1700000000000\n\
+                     m{k=\"pinf\"} +Inf\n\
+                     m{k=\"ninf\"} -Inf 1700000000000\n\
+                     m{k=\"nan\"} NaN\n\
+                     m{k=\"dots\"} 1.2.3\n\
+                     m{k=\"word\"} fast\n\
+                     m{k=\"bad_ts\"} 1 soon\n\
+                     m{k=\"extra\"} 1 1700000000000 x\n\
+                     m{k=\"missing\"}\n\
+                     broken{k=\"1\" 2\n";
+        let parse = |strict| {
+            let mut parser = FamilyParser::default().strict_values(strict);
+            input.lines().for_each(|line| parser.push_line(line));
+            parser.finish()
+        };
+
+        let (families, stats) = parse(true);
+        let kept: Vec<_> = families[0]
+            .samples
+            .iter()
+            .map(|s| sample_labels(&s.raw_line)[0].1.clone())
+            .collect();
+        assert_eq!(
+            kept,
+            ["int", "float", "sci", "sci_upper", "pinf", "ninf", "nan"]
+        );
+        assert_eq!(stats.invalid_values, 5);
+        assert_eq!(stats.malformed, 1);
+
+        // Without strict checking only unparsable values are skipped, as malformed.
+        let (families, stats) = parse(false);
+        assert_eq!(families[0].samples.len(), 9);
+        assert_eq!(stats.invalid_values, 0);
+        assert_eq!(stats.malformed, 4);
+    }
+
+    #[test]
+    fn canonical_labels_do_not_depend_on_upstream_order() {
+        let render = |input: &str| {
+            let mut families = parse_families(input).0;
+            canonicalize_labels(&mut families);
+            crate::state::render_families(&families)
+        };
+        let a = render(
+            "# TYPE m counter\n\
+             m{b=\"2\",a=\"x,} y\",c=\"3\"} 7 1700000000000 # {trace_id=\"z\"} 1\n\
+             {\"m.dotted\",z=\"1\",y=\"2\"} 1.5e3\n\
+             plain 1\n",
+        );
+        let b = render(
+            "# TYPE m counter\n\
+             m{c=\"3\",b=\"2\",a=\"x,} y\"} 7 1700000000000 # {trace_id=\"z\"} 1\n\
+             {\"m.dotted\",y=\"2\",z=\"1\"} 1.5e3\n\
+             plain 1\n",
+        );
+        assert_eq!(a, b);
+        assert_eq!(
+            a,
+            "# TYPE m counter\n\
+             m{a=\"x,} y\",b=\"2\",c=\"3\"} 7 1700000000000 # {trace_id=\"z\"} 1\n\
+             {\"m.dotted\",y=\"2\",z=\"1\"} 1.5e3\n\
+             plain 1\n"
+        );
+    }
+
+    #[test]
+    fn samples_with_old_timestamps_are_dropped() {
+        let input = "# TYPE m gauge\n\
+                     m{a=\"fresh\"} 1 5000\n\
+                     m{a=\"stale\"} 2 1000\n\
+                     m{a=\"untimed\"} 3\n\
+                     m{a=\"inf\"} +Inf 1000\n\
+                     m{a=\"nan\"} NaN 6000\n\
+                     m{a=\"nan_untimed\"} NaN\n\
+                     m{a=\"edge\"} -Inf 4000\n\
+                     gone 1 10\n";
+        let mut families = parse_families(input).0;
+        assert_eq!(drop_samples_older_than(&mut families, 4000), 3);
+        assert_eq!(families.len(), 1);
+        let kept: Vec<_> = families[0]
+            .samples
+            .iter()
+            .map(|s| s.raw_line.trim_end())
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                "m{a=\"fresh\"} 1 5000",
+                "m{a=\"untimed\"} 3",
+                "m{a=\"nan\"} NaN 6000",
+                "m{a=\"nan_untimed\"} NaN",
+                "m{a=\"edge\"} -Inf 4000",
+            ]
+        );
+    }
+
+    #[test]
+    fn streamed_chunks_match_whole_body_parse() {
+        let input = "# HELP \"é.m\" Ünïcode.\r\n# TYPE \"é.m\" gauge\r\n\
+                     {\"é.m\",a=\"ü\"} 1\n\nbad line\nup 1\nother{x=\"1\"} 2";
+        let (want, want_stats) = parse_families(input);
+        // Every chunk size, including ones that split multi-byte characters.
+        for size in 1..=input.len() {
+            let mut parser = FamilyParser::default();
+            let mut lines = LineSplitter::default();
+            for chunk in input.as_bytes().chunks(size) {
+                lines.push(chunk, |line| parser.push_line(line));
+            }
+            lines.finish(|line| parser.push_line(line));
+            let (got, stats) = parser.finish();
+            assert_eq!(stats, want_stats, "chunk size {size}");
+            let render = |fs: &[ParsedFamily]| -> Vec<String> {
+                fs.iter()
+                    .flat_map(|f| f.samples.iter().map(|s| s.raw_line.clone()))
+                    .collect()
+            };
+            assert_eq!(render(&got), render(&want), "chunk size {size}");
+        }
+        assert_eq!(want_stats.malformed, 1);
+        assert_eq!(want.len(), 3);
+    }
+
+    #[test]
+    fn line_splitter_counts_invalid_utf8_across_chunks() {
+        let input = b"up{a=\"\xff\"} 1\nok 1\nlast{b=\"\xc3\"} 2";
+        for size in 1..=input.len() {
+            let mut lines = LineSplitter::default();
+            let mut got = Vec::new();
+            for chunk in input.chunks(size) {
+                lines.push(chunk, |line| got.push(line.to_owned()));
+            }
+            assert_eq!(lines.finish(|line| got.push(line.to_owned())), 2);
+            assert_eq!(
+                got,
+                ["up{a=\"\u{fffd}\"} 1", "ok 1", "last{b=\"\u{fffd}\"} 2"]
+            );
+        }
+        assert_eq!(decode_lossy(b"fine").1, 0);
+    }
+
+    #[test]
+    fn sample_value_parses_special_values() {
+        assert_eq!(sample_value("up 1\n"), Some(1.0));
+        assert_eq!(sample_value("m{a=\"x y\"} 2.5 1000\n"), Some(2.5));
+        assert_eq!(sample_value("m{le=\"+Inf\"} +Inf\n"), Some(f64::INFINITY));
+        assert!(sample_value("m NaN\n").unwrap().is_nan());
+        assert_eq!(sample_value("m{a=\"1\"}\n"), None);
+    }
+
+    #[test]
+    fn parse_stats_count_good_and_garbage_lines() {
+        let input = "\
+# HELP up Up.
+# TYPE up gauge
+up 1
+# a plain comment
+
+up{job=\"a\"} 0 1700000000000
+this is not a metric
+9starts_with_digit 1
+missing_value
+unclosed{a=\"1\" 1
+bad_value{a=\"1\"} abc
+";
+        let (families, stats) = parse_families(input);
+        assert_eq!(
+            stats,
+            ParseStats {
+                lines: 10,
+                samples: 2,
+                comments: 3,
+                malformed: 5,
+                invalid_utf8: 0,
+                invalid_values: 0,
+            }
+        );
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].samples.len(), 2);
+    }
 }
@@ -0,0 +1,393 @@
+use std::fmt::Write;
+
+use anyhow::{Context, bail};
+
+use crate::config::is_valid_label_name;
+use crate::metrics::SampleValue;
+use crate::parser::{decode_lossy, escape_label_value, is_valid_metric_name};
+
+/// `Accept` sent by sources with `accept_protobuf`: the delimited protobuf
+/// exposition format first, the text format as the fallback.
+pub const PROTOBUF_ACCEPT: &str = "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3,*/*;q=0.1";
+
+/// Whether a response `Content-Type` is the protobuf exposition format.
+pub fn is_protobuf(content_type: &str) -> bool {
+    content_type.split(';').next().is_some_and(|mime| {
+        mime.trim()
+            .eq_ignore_ascii_case("application/vnd.google.protobuf")
+    })
+}
+
+/// Decodes a body of varint-length-delimited `io.prometheus.client.MetricFamily`
+/// messages and renders it back as text exposition, so the result goes
+/// through the same transforms and parser as a text body:
+///
+/// ```text
+/// MetricFamily { string name = 1; string help = 2; MetricType type = 3; repeated Metric metric = 4; }
+/// Metric       { repeated LabelPair label = 1; Gauge gauge = 2; Counter counter = 3;
+///                Summary summary = 4; Untyped untyped = 5; int64 timestamp_ms = 6;
+///                Histogram histogram = 7; }
+/// ```
+///
+/// Summaries and classic histograms expand into their `quantile`/`_bucket`,
+/// `_sum` and `_count` series, with a `+Inf` bucket added when missing. A
+/// gauge histogram is rendered as a histogram, and a native histogram without
+/// classic buckets keeps only `_sum` and `_count`. Exemplars, units and
+/// created timestamps are dropped.
+///
+/// Invalid UTF-8 in strings is replaced with U+FFFD and counted, as for a
+/// text body. `Accept` doesn't offer `escaping=allow-utf-8`, so metric and
+/// label names must be in the text format's legacy character set; metrics
+/// with other names are left out and counted.
+pub fn decode_to_text(body: &[u8]) -> anyhow::Result<Decoded> {
+    let mut decoded = Decoded {
+        text: String::with_capacity(body.len() * 2),
+        invalid_utf8: 0,
+        invalid_names: 0,
+    };
+    let mut reader = Reader(body);
+    let mut index = 0;
+    while !reader.0.is_empty() {
+        let len = reader.varint()? as usize;
+        let message = reader
+            .take(len)
+            .with_context(|| format!("metric family {index} is truncated"))?;
+        let family = MetricFamily::decode(message, &mut decoded.invalid_utf8)
+            .with_context(|| format!("metric family {index} is malformed"))?;
+        decoded.invalid_names += family.render(&mut decoded.text);
+        index += 1;
+    }
+    Ok(decoded)
+}
+
+/// A protobuf body rendered as text exposition by [`decode_to_text`].
+#[derive(Debug)]
+pub struct Decoded {
+    pub text: String,
+    /// Invalid UTF-8 sequences replaced with U+FFFD.
+    pub invalid_utf8: usize,
+    /// Metrics left out for a metric or label name outside the legacy
+    /// character set.
+    pub invalid_names: usize,
+}
+
+/// A protobuf `string`, decoded lossily; invalid sequences are added to
+/// `invalid_utf8`.
+fn lossy_string(bytes: &[u8], invalid_utf8: &mut usize) -> String {
+    let (text, invalid) = decode_lossy(bytes);
+    *invalid_utf8 += invalid;
+    text.into_owned()
+}
+
+const COUNTER: u64 = 0;
+const GAUGE: u64 = 1;
+const SUMMARY: u64 = 2;
+const HISTOGRAM: u64 = 4;
+const GAUGE_HISTOGRAM: u64 = 5;
+
+#[derive(Default)]
+struct MetricFamily {
+    name: String,
+    help: Option<String>,
+    metric_type: u64,
+    metrics: Vec<Metric>,
+}
+
+#[derive(Default)]
+struct Metric {
+    labels: Vec<(String, String)>,
+    /// Value of the gauge, counter or untyped sub-message matching the type.
+    gauge: Option<f64>,
+    counter: Option<f64>,
+    untyped: Option<f64>,
+    summary: Summary,
+    histogram: Histogram,
+    timestamp_ms: Option<i64>,
+}
+
+#[derive(Default)]
+struct Summary {
+    count: u64,
+    sum: f64,
+    quantiles: Vec<(f64, f64)>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    count: f64,
+    sum: f64,
+    /// `(upper_bound, cumulative_count)`.
+    buckets: Vec<(f64, f64)>,
+}
+
+impl MetricFamily {
+    fn decode(mut r: Reader<'_>, invalid_utf8: &mut usize) -> anyhow::Result<Self> {
+        let mut family = MetricFamily::default();
+        while let Some((field, value)) = r.field()? {
+            match (field, value) {
+                (1, Value::Len(b)) => family.name = lossy_string(b, invalid_utf8),
+                (2, Value::Len(b)) => family.help = Some(lossy_string(b, invalid_utf8)),
+                (3, Value::Varint(v)) => family.metric_type = v,
+                (4, Value::Len(b)) => family
+                    .metrics
+                    .push(Metric::decode(Reader(b), invalid_utf8)?),
+                _ => {}
+            }
+        }
+        if family.name.is_empty() {
+            bail!("metric family has no name");
+        }
+        Ok(family)
+    }
+
+    /// Appends the family as text and returns the number of metrics left out
+    /// for invalid names.
+    fn render(&self, out: &mut String) -> usize {
+        let name = &self.name;
+        if !is_valid_metric_name(name) {
+            return self.metrics.len();
+        }
+        if let Some(help) = &self.help {
+            let help = help.replace('\\', r"\\").replace('\n', r"\n");
+            let _ = writeln!(out, "# HELP {name} {help}");
+        }
+        let type_name = match self.metric_type {
+            COUNTER => "counter",
+            GAUGE => "gauge",
+            SUMMARY => "summary",
+            HISTOGRAM | GAUGE_HISTOGRAM => "histogram",
+            // UNTYPED (3), and types newer than this decoder.
+            _ => "untyped",
+        };
+        let _ = writeln!(out, "# TYPE {name} {type_name}");
+        let mut invalid = 0;
+        for metric in &self.metrics {
+            if !metric.labels.iter().all(|(k, _)| is_valid_label_name(k)) {
+                invalid += 1;
+                continue;
+            }
+            let line = |out: &mut String, suffix: &str, extra: Option<(&str, f64)>, value: f64| {
+                metric.render_line(out, name, suffix, extra, value)
+            };
+            match self.metric_type {
+                COUNTER => line(out, "", None, metric.counter.unwrap_or_default()),
+                GAUGE => line(out, "", None, metric.gauge.unwrap_or_default()),
+                SUMMARY => {
+                    let s = &metric.summary;
+                    for &(q, v) in &s.quantiles {
+                        line(out, "", Some(("quantile", q)), v);
+                    }
+                    line(out, "_sum", None, s.sum);
+                    line(out, "_count", None, s.count as f64);
+                }
+                HISTOGRAM | GAUGE_HISTOGRAM => {
+                    let h = &metric.histogram;
+                    for &(le, count) in &h.buckets {
+                        line(out, "_bucket", Some(("le", le)), count);
+                    }
+                    let has_inf = h.buckets.iter().any(|&(le, _)| le == f64::INFINITY);
+                    if !h.buckets.is_empty() && !has_inf {
+                        line(out, "_bucket", Some(("le", f64::INFINITY)), h.count);
+                    }
+                    line(out, "_sum", None, h.sum);
+                    line(out, "_count", None, h.count);
+                }
+                _ => line(out, "", None, metric.untyped.unwrap_or_default()),
+            }
+        }
+        invalid
+    }
+}
+
+impl Metric {
+    fn decode(mut r: Reader<'_>, invalid_utf8: &mut usize) -> anyhow::Result<Self> {
+        let mut metric = Metric::default();
+        while let Some((field, value)) = r.field()? {
+            match (field, value) {
+                (1, Value::Len(b)) => {
+                    let (mut name, mut value) = (String::new(), String::new());
+                    let mut pair = Reader(b);
+                    while let Some(field) = pair.field()? {
+                        match field {
+                            (1, Value::Len(b)) => name = lossy_string(b, invalid_utf8),
+                            (2, Value::Len(b)) => value = lossy_string(b, invalid_utf8),
+                            _ => {}
+                        }
+                    }
+                    metric.labels.push((name, value));
+                }
+                (2, Value::Len(b)) => metric.gauge = Some(single_value(Reader(b))?),
+                (3, Value::Len(b)) => metric.counter = Some(single_value(Reader(b))?),
+                (4, Value::Len(b)) => metric.summary = Summary::decode(Reader(b))?,
+                (5, Value::Len(b)) => metric.untyped = Some(single_value(Reader(b))?),
+                (6, Value::Varint(v)) => metric.timestamp_ms = Some(v as i64),
+                (7, Value::Len(b)) => metric.histogram = Histogram::decode(Reader(b))?,
+                _ => {}
+            }
+        }
+        Ok(metric)
+    }
+
+    /// Writes `{name}{suffix}{labels} value [timestamp]`, with `extra` (`le`
+    /// or `quantile`) after the metric's own labels.
+    fn render_line(
+        &self,
+        out: &mut String,
+        name: &str,
+        suffix: &str,
+        extra: Option<(&str, f64)>,
+        value: f64,
+    ) {
+        out.push_str(name);
+        out.push_str(suffix);
+        if !self.labels.is_empty() || extra.is_some() {
+            out.push('{');
+            for (i, (k, v)) in self.labels.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                let _ = write!(out, "{k}=\"{}\"", escape_label_value(v));
+            }
+            if let Some((k, v)) = extra {
+                if !self.labels.is_empty() {
+                    out.push(',');
+                }
+                let _ = write!(out, "{k}=\"");
+                v.write_to(out);
+                out.push('"');
+            }
+            out.push('}');
+        }
+        out.push(' ');
+        value.write_to(out);
+        if let Some(ts) = self.timestamp_ms {
+            let _ = write!(out, " {ts}");
+        }
+        out.push('\n');
+    }
+}
+
+impl Summary {
+    fn decode(mut r: Reader<'_>) -> anyhow::Result<Self> {
+        let mut summary = Summary::default();
+        while let Some(field) = r.field()? {
+            match field {
+                (1, Value::Varint(v)) => summary.count = v,
+                (2, Value::Fixed64(v)) => summary.sum = f64::from_bits(v),
+                (3, Value::Len(b)) => {
+                    let (mut q, mut v) = (0.0, 0.0);
+                    let mut quantile = Reader(b);
+                    while let Some(field) = quantile.field()? {
+                        match field {
+                            (1, Value::Fixed64(x)) => q = f64::from_bits(x),
+                            (2, Value::Fixed64(x)) => v = f64::from_bits(x),
+                            _ => {}
+                        }
+                    }
+                    summary.quantiles.push((q, v));
+                }
+                _ => {}
+            }
+        }
+        Ok(summary)
+    }
+}
+
+impl Histogram {
+    fn decode(mut r: Reader<'_>) -> anyhow::Result<Self> {
+        let mut histogram = Histogram::default();
+        let mut count_float = None;
+        while let Some(field) = r.field()? {
+            match field {
+                (1, Value::Varint(v)) => histogram.count = v as f64,
+                (2, Value::Fixed64(v)) => histogram.sum = f64::from_bits(v),
+                (3, Value::Len(b)) => {
+                    let (mut le, mut count, mut count_float) = (0.0, 0.0, None);
+                    let mut bucket = Reader(b);
+                    while let Some(field) = bucket.field()? {
+                        match field {
+                            (1, Value::Varint(x)) => count = x as f64,
+                            (2, Value::Fixed64(x)) => le = f64::from_bits(x),
+                            (4, Value::Fixed64(x)) => count_float = Some(f64::from_bits(x)),
+                            _ => {}
+                        }
+                    }
+                    histogram.buckets.push((le, count_float.unwrap_or(count)));
+                }
+                (4, Value::Fixed64(v)) => count_float = Some(f64::from_bits(v)),
+                _ => {}
+            }
+        }
+        // Float histograms carry their counts only in the `_float` fields.
+        if let Some(count) = count_float {
+            histogram.count = count;
+        }
+        Ok(histogram)
+    }
+}
+
+/// The `double value = 1` of a `Gauge`, `Counter` or `Untyped` message.
+fn single_value(mut r: Reader<'_>) -> anyhow::Result<f64> {
+    let mut value = 0.0;
+    while let Some(field) = r.field()? {
+        if let (1, Value::Fixed64(v)) = field {
+            value = f64::from_bits(v);
+        }
+    }
+    Ok(value)
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Len(&'a [u8]),
+    Fixed32,
+}
+
+/// Minimal protobuf wire-format reader over a borrowed buffer.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> anyhow::Result<u64> {
+        let mut value = 0u64;
+        for (i, &byte) in self.0.iter().enumerate().take(10) {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte < 0x80 {
+                self.0 = &self.0[i + 1..];
+                return Ok(value);
+            }
+        }
+        bail!("invalid varint");
+    }
+
+    fn take(&mut self, len: usize) -> anyhow::Result<Reader<'a>> {
+        if len > self.0.len() {
+            bail!("length {len} runs past the end of the message");
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(Reader(head))
+    }
+
+    /// The next field number and value, or `None` at the end of the message.
+    fn field(&mut self) -> anyhow::Result<Option<(u64, Value<'a>)>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => Value::Fixed64(u64::from_le_bytes(self.take(8)?.0.try_into()?)),
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Len(self.take(len)?.0)
+            }
+            5 => {
+                self.take(4)?;
+                Value::Fixed32
+            }
+            wire_type => bail!("unsupported wire type {wire_type}"),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
@@ -0,0 +1,194 @@
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use reqwest::Client;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE, USER_AGENT};
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+
+use crate::config::{AppConfig, RemoteWriteConfig, redact_url};
+use crate::parser::{
+    extract_metric_name, parse_families, sample_labels, sample_timestamp, sample_value,
+};
+use crate::scraper::DEFAULT_USER_AGENT;
+use crate::state::ShardedState;
+
+/// Pushes the freshly scraped `state` to every `[[remote_write]]` endpoint
+/// concurrently, each getting only its configured shards. Failed pushes are
+/// logged and not retried; the next cycle pushes fresh data.
+pub async fn push_all(config: Arc<AppConfig>, state: Arc<ShardedState>, client: Client) {
+    let scraped_at_ms = state
+        .scraped_at
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64);
+    let user_agent: Arc<str> = config
+        .user_agent
+        .as_deref()
+        .unwrap_or(DEFAULT_USER_AGENT)
+        .into();
+    let mut pushes = JoinSet::new();
+    for endpoint in &config.remote_write {
+        let range = endpoint
+            .shard_range(config.num_shards)
+            .expect("remote_write shards validated at load");
+        let texts: Vec<_> = state.shards[*range.start() as usize..=*range.end() as usize]
+            .iter()
+            .map(|s| s.text.clone())
+            .collect();
+        let endpoint = endpoint.clone();
+        let client = client.clone();
+        let user_agent = user_agent.clone();
+        pushes.spawn(async move {
+            let bodies = encode_write_requests(
+                texts
+                    .iter()
+                    .map(|t| std::str::from_utf8(t).unwrap_or_default()),
+                scraped_at_ms,
+                MAX_SERIES_PER_REQUEST,
+            );
+            // In order, giving up on the first failure: the rest would most
+            // likely fail the same way.
+            for body in bodies {
+                if !push(&client, &endpoint, &user_agent, body).await {
+                    break;
+                }
+            }
+        });
+    }
+    while pushes.join_next().await.is_some() {}
+}
+
+/// Series sent per `WriteRequest`, as Prometheus' default
+/// `max_samples_per_send`; receivers cap the request size.
+const MAX_SERIES_PER_REQUEST: usize = 2000;
+
+/// Sends one request; returns whether it was accepted.
+async fn push(
+    client: &Client,
+    endpoint: &RemoteWriteConfig,
+    user_agent: &str,
+    body: Vec<u8>,
+) -> bool {
+    let url = redact_url(&endpoint.url);
+    let body = match snap::raw::Encoder::new().compress_vec(&body) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(url = %url, error = %e, "failed to snappy-compress remote write request");
+            return false;
+        }
+    };
+    let mut req = client
+        .post(&endpoint.url)
+        .header(CONTENT_TYPE, "application/x-protobuf")
+        .header(CONTENT_ENCODING, "snappy")
+        .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+        .header(USER_AGENT, user_agent)
+        .timeout(Duration::from_secs(endpoint.timeout_secs));
+    for (k, v) in &endpoint.headers {
+        req = req.header(k.as_str(), v.as_str());
+    }
+    match req
+        .body(body)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+    {
+        Ok(_) => {
+            debug!(url = %url, "pushed shards via remote write");
+            true
+        }
+        Err(e) => {
+            warn!(
+                url = %url,
+                error = %e.without_url(),
+                "failed to push shards via remote write"
+            );
+            false
+        }
+    }
+}
+
+/// Encodes every series of the rendered shard `texts` as uncompressed
+/// Prometheus remote-write `WriteRequest` protobufs of at most `max_series`
+/// series each:
+///
+/// ```text
+/// WriteRequest { repeated TimeSeries timeseries = 1; }
+/// TimeSeries   { repeated Label labels = 1; repeated Sample samples = 2; }
+/// Label        { string name = 1; string value = 2; }
+/// Sample       { double value = 1; int64 timestamp = 2; }
+/// ```
+///
+/// Each series carries its sample name as `__name__` and labels sorted by
+/// name. Samples without an explicit timestamp get `default_ts_ms`, the
+/// scrape time. HELP/TYPE metadata is not sent.
+pub fn encode_write_requests<'a>(
+    texts: impl IntoIterator<Item = &'a str>,
+    default_ts_ms: i64,
+    max_series: usize,
+) -> Vec<Vec<u8>> {
+    let mut requests = Vec::new();
+    let mut out = Vec::new();
+    let mut in_request = 0;
+    let mut series = Vec::new();
+    let mut scratch = Vec::new();
+    for text in texts {
+        for sample in parse_families(text).0.iter().flat_map(|f| &f.samples) {
+            let line = &sample.raw_line;
+            let mut labels = sample_labels(line);
+            labels.push(("__name__".to_owned(), extract_metric_name(line).to_owned()));
+            labels.sort_by(|a, b| a.0.cmp(&b.0));
+
+            series.clear();
+            for (name, value) in &labels {
+                scratch.clear();
+                put_bytes(&mut scratch, 1, name.as_bytes());
+                put_bytes(&mut scratch, 2, value.as_bytes());
+                put_bytes(&mut series, 1, &scratch);
+            }
+            scratch.clear();
+            put_key(&mut scratch, 1, WIRE_FIXED64);
+            scratch.extend_from_slice(&sample_value(line).unwrap_or(f64::NAN).to_le_bytes());
+            put_key(&mut scratch, 2, WIRE_VARINT);
+            put_varint(
+                &mut scratch,
+                sample_timestamp(line).unwrap_or(default_ts_ms) as u64,
+            );
+            put_bytes(&mut series, 2, &scratch);
+
+            put_bytes(&mut out, 1, &series);
+            in_request += 1;
+            if in_request == max_series {
+                requests.push(std::mem::take(&mut out));
+                in_request = 0;
+            }
+        }
+    }
+    if in_request > 0 {
+        requests.push(out);
+    }
+    requests
+}
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+
+fn put_key(buf: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(buf, u64::from(field << 3 | u32::from(wire_type)));
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// A length-delimited field: strings, bytes and embedded messages.
+fn put_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_key(buf, field, WIRE_LEN);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
@@ -1,138 +1,984 @@
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use reqwest::Client;
-use tokio::task::JoinSet;
+use anyhow::Context;
+use bytes::Bytes;
+use rand::Rng;
+use reqwest::header::{ACCEPT, CONTENT_TYPE, HeaderMap, USER_AGENT};
+use reqwest::{Client, ClientBuilder, NoProxy, Proxy, StatusCode};
+use tokio::sync::Semaphore;
+use tokio::task::{JoinHandle, JoinSet};
 use tokio::time;
 use tracing::{error, info, warn};
 
-use crate::config::{AppConfig, SourceConfig};
-use crate::parser::{inject_labels, merge_families, parse_families};
-use crate::state::{ShardedState, SharedState, SourceStatus, build_shards};
+use crate::cache;
+use crate::config::{
+    AppConfig, DedupScope, DuplicateLabelAction, InvalidUtf8Action, MergeStrategy, SourceConfig,
+    TimestampAction, TypeConflictAction, redact_url,
+};
+use crate::discovery::Discovery;
+use crate::hasher::{HashAlgorithm, ShardLayout};
+use crate::parser::{
+    FamilyParser, LineSplitter, ParseStats, ParsedFamily, canonicalize_labels, cap_families,
+    decode_lossy, dedupe_series_labels, drop_families_with_prefix, drop_samples_older_than,
+    drop_series_over_label_limit, enforce_timestamp_tolerance, group_families, inject_labels,
+    label_name_counts, merge_families, sum_families,
+};
+use crate::protobuf::{self, PROTOBUF_ACCEPT};
+use crate::remote_write;
+use crate::state::{
+    DROPPED_FAMILY_SAMPLES, Heartbeat, ScrapeOutcome, ShardedState, SharedState, SourceStatus,
+    build_shards, compare_algorithms, empty_state,
+};
+use crate::transform::apply_transforms;
+#[cfg(unix)]
+use crate::unix_socket;
 
-pub async fn run_scrape_loop(config: Arc<AppConfig>, state: SharedState) {
-    let client = Client::builder()
-        .build()
+/// Prefix shared by every family the proxy serves at `/metrics`.
+const SELF_METRICS_PREFIX: &str = "prom_reaper_";
+
+/// `User-Agent` sent upstream unless `user_agent` or a source header sets one.
+pub(crate) const DEFAULT_USER_AGENT: &str = concat!("prom_the_reaper/", env!("CARGO_PKG_VERSION"));
+
+pub async fn run_scrape_loop(
+    config: Arc<AppConfig>,
+    state: SharedState,
+    heartbeat: Arc<Heartbeat>,
+) {
+    let client = client_builder(&config)
+        .and_then(|b| Ok(b.build()?))
         .expect("failed to build HTTP client");
+    let static_targets = static_targets(&config, &client);
+    let mut discovery = Discovery::new(&config.http_sd);
+    let layout = config.shard_layout();
+    let view_layouts = config.view_layouts();
 
-    let mut interval = time::interval(Duration::from_secs(config.scrape_interval_secs));
+    let interval = Duration::from_secs(config.scrape_interval_secs);
+    let jitter = Duration::from_secs(config.scrape_jitter_secs);
+    let mut cache_write: Option<JoinHandle<()>> = None;
+    let mut remote_write_push: Option<JoinHandle<()>> = None;
 
     loop {
-        interval.tick().await;
+        heartbeat.beat();
         info!("starting scrape cycle");
         let scrape_start = Instant::now();
 
-        let results = scrape_all(&client, &config.sources).await;
+        let mut targets = static_targets.clone();
+        targets.extend(
+            discovery
+                .targets(&client)
+                .await
+                .into_iter()
+                .map(|s| (s, client.clone())),
+        );
+
+        let previous = state.load_full();
+        match scrape_cycle(&targets, &config, &layout, &view_layouts, &previous).await {
+            Some(new_state) => {
+                let new_state = Arc::new(new_state);
+                state.store(new_state.clone());
+                // Never overlapping: both writes would share the temporary file.
+                if let Some(dir) = config.cache_dir.clone() {
+                    if cache_write.as_ref().is_some_and(|h| !h.is_finished()) {
+                        warn!("previous shard cache write still running, skipping this cycle");
+                    } else {
+                        let (state, fingerprint) = (new_state.clone(), layout.fingerprint());
+                        cache_write = Some(tokio::task::spawn_blocking(move || {
+                            if let Err(e) = cache::persist(&dir, &state, fingerprint) {
+                                warn!(error = format!("{e:#}"), "failed to write shard cache");
+                            }
+                        }));
+                    }
+                }
+                // Detached so a slow receiver never delays the next cycle, but
+                // never overlapping: a later push could land first, and
+                // receivers reject out-of-order samples.
+                if !config.remote_write.is_empty() {
+                    if remote_write_push.as_ref().is_some_and(|h| !h.is_finished()) {
+                        warn!("previous remote write push still running, skipping this cycle");
+                    } else {
+                        remote_write_push = Some(tokio::spawn(remote_write::push_all(
+                            config.clone(),
+                            new_state,
+                            client.clone(),
+                        )));
+                    }
+                }
+                info!(
+                    duration_ms = scrape_start.elapsed().as_millis() as u64,
+                    "scrape cycle complete"
+                );
+            }
+            None => error!("all sources failed, keeping stale data"),
+        }
+
+        // Measured from the cycle start so scrape time doesn't stretch the period.
+        let period = jittered_interval(interval, jitter, &mut rand::rng());
+        time::sleep(period.saturating_sub(scrape_start.elapsed())).await;
+    }
+}
+
+/// Runs a single scrape cycle of every source (static and discovered) and
+/// returns the state the loop would serve, or `None` if every source failed.
+pub async fn scrape_once(config: Arc<AppConfig>) -> anyhow::Result<Option<ShardedState>> {
+    let client = client_builder(&config)?.build()?;
+    let mut targets = static_targets(&config, &client);
+    targets.extend(
+        Discovery::new(&config.http_sd)
+            .targets(&client)
+            .await
+            .into_iter()
+            .map(|s| (s, client.clone())),
+    );
+    let layout = config.shard_layout();
+    let view_layouts = config.view_layouts();
+    Ok(scrape_cycle(&targets, &config, &layout, &view_layouts, &empty_state()).await)
+}
+
+/// Pairs each configured source with its client: a dedicated one when its
+/// transport options need it, otherwise a clone of `client`.
+fn static_targets(config: &AppConfig, client: &Client) -> Vec<ScrapeTarget> {
+    config
+        .sources
+        .iter()
+        .map(|s| {
+            if s.insecure_skip_verify {
+                warn!(
+                    url = %redact_url(&s.url),
+                    "insecure_skip_verify is enabled: TLS certificates from this source are NOT verified"
+                );
+            }
+            let client = build_client(config, s)
+                .expect("failed to build HTTP client")
+                .unwrap_or_else(|| client.clone());
+            (Arc::new(s.clone()), client)
+        })
+        .collect()
+}
+
+/// Scrapes `targets` and builds the next state from them. Returns `None` when
+/// every source failed, so the caller can keep serving stale data. Shards
+/// unchanged since `previous` are carried over rather than re-rendered.
+async fn scrape_cycle(
+    targets: &[ScrapeTarget],
+    config: &Arc<AppConfig>,
+    layout: &ShardLayout,
+    view_layouts: &BTreeMap<String, ShardLayout>,
+    previous: &ShardedState,
+) -> Option<ShardedState> {
+    let results = scrape_all(targets, config).await;
 
-        let mut all_families = Vec::new();
-        let mut source_statuses = Vec::new();
-        let mut any_success = false;
+    let mut all_families = Vec::new();
+    let mut source_statuses = Vec::new();
+    let mut any_success = false;
 
-        for (url, result) in results {
-            match result {
-                Ok((families, duration)) => {
-                    info!(
+    for (url, result) in results {
+        match result {
+            Ok(scrape) => {
+                info!(
+                    url = %url,
+                    families = scrape.families.len(),
+                    duration_ms = scrape.duration.as_millis() as u64,
+                    "scraped source"
+                );
+                if scrape.parse_stats.malformed > 0 {
+                    warn!(
                         url = %url,
-                        families = families.len(),
-                        duration_ms = duration.as_millis() as u64,
-                        "scraped source"
+                        count = scrape.parse_stats.malformed,
+                        "skipped malformed lines"
                     );
-                    source_statuses.push(SourceStatus {
-                        url: url.clone(),
-                        success: true,
-                        duration,
-                        metric_families: families.len(),
-                    });
-                    all_families.extend(families);
-                    any_success = true;
                 }
-                Err((e, duration)) => {
-                    warn!(url = %url, error = %e, "failed to scrape source");
-                    source_statuses.push(SourceStatus {
-                        url,
-                        success: false,
-                        duration,
-                        metric_families: 0,
-                    });
+                if scrape.parse_stats.invalid_values > 0 {
+                    warn!(
+                        url = %url,
+                        count = scrape.parse_stats.invalid_values,
+                        "skipped samples with invalid values"
+                    );
                 }
+                if scrape.parse_stats.invalid_utf8 > 0 {
+                    warn!(
+                        url = %url,
+                        count = scrape.parse_stats.invalid_utf8,
+                        "replaced invalid UTF-8 sequences"
+                    );
+                }
+                for (reason, count) in &scrape.dropped_series {
+                    warn!(url = %url, reason, count, "dropped series");
+                }
+                if scrape.stripped_timestamps > 0 {
+                    warn!(
+                        url = %url,
+                        count = scrape.stripped_timestamps,
+                        "stripped out-of-tolerance timestamps"
+                    );
+                }
+                source_statuses.push(SourceStatus {
+                    url: url.clone(),
+                    success: true,
+                    outcome: scrape.outcome,
+                    duration: scrape.duration,
+                    fetch_duration: scrape.fetch,
+                    metric_families: scrape.families.len(),
+                    body_bytes: scrape.body_bytes,
+                    parse_stats: scrape.parse_stats,
+                    series_count: scrape.families.iter().map(|f| f.samples.len()).sum(),
+                    dropped_series: scrape.dropped_series,
+                    dropped_families: scrape.dropped_families,
+                    stripped_timestamps: scrape.stripped_timestamps,
+                });
+                all_families.extend(scrape.families);
+                any_success = true;
             }
-        }
-
-        if any_success {
-            let (all_families, merge_stats) = merge_families(all_families);
-            if merge_stats.duplicate_count > 0 {
+            Err(failure) => {
                 warn!(
-                    duplicate_count = merge_stats.duplicate_count,
-                    examples = %merge_stats.examples.join(", "),
-                    "duplicate series detected across sources, first-seen value kept"
+                    url = %url,
+                    outcome = failure.outcome.kind(),
+                    error = %failure.error,
+                    "failed to scrape source"
                 );
+                source_statuses.push(SourceStatus {
+                    url,
+                    success: false,
+                    outcome: failure.outcome,
+                    duration: failure.duration,
+                    fetch_duration: failure.fetch,
+                    parse_stats: failure.parse_stats,
+                    ..Default::default()
+                });
             }
-            let shards = build_shards(all_families, config.num_shards);
-            let new_state = Arc::new(ShardedState {
-                shards,
-                last_scrape: Instant::now(),
-                source_status: source_statuses,
-            });
-            state.store(new_state);
-            info!(
-                duration_ms = scrape_start.elapsed().as_millis() as u64,
-                "scrape cycle complete"
+        }
+    }
+
+    if !any_success {
+        return None;
+    }
+    let drop_type_conflicts = config.on_type_conflict == TypeConflictAction::Drop;
+    let (mut all_families, merge_stats) = match (config.dedup_scope, config.merge_strategy) {
+        (DedupScope::Global, MergeStrategy::FirstWins) => {
+            merge_families(all_families, config.dedup, drop_type_conflicts)
+        }
+        (DedupScope::Global, MergeStrategy::Sum) => {
+            sum_families(all_families, config.dedup, drop_type_conflicts)
+        }
+        (DedupScope::None, _) => group_families(all_families, drop_type_conflicts),
+    };
+    if merge_stats.summed_count > 0 {
+        info!(
+            summed_count = merge_stats.summed_count,
+            "summed counter and histogram series exposed by several sources"
+        );
+    }
+    if merge_stats.duplicate_count > 0 {
+        warn!(
+            duplicate_count = merge_stats.duplicate_count,
+            examples = %merge_stats.examples.join(", "),
+            keep = ?config.dedup,
+            "duplicate series detected across sources"
+        );
+    }
+    if merge_stats.type_conflicts > 0 {
+        warn!(
+            type_conflicts = merge_stats.type_conflicts,
+            dropped_series = merge_stats.type_conflict_dropped,
+            examples = %merge_stats.type_conflict_examples.join(", "),
+            "conflicting TYPE declared for the same metric across sources"
+        );
+    }
+    // Bounds per-cycle shard building when an exporter floods family names.
+    let mut families_over_limit = 0;
+    if let Some(max) = config.max_families {
+        let (dropped, series) = cap_families(&mut all_families, max);
+        if !dropped.is_empty() {
+            warn!(
+                families = dropped.len(),
+                series,
+                max_families = max,
+                examples = %dropped[..dropped.len().min(DROPPED_FAMILY_SAMPLES)].join(", "),
+                "dropped families over max_families"
             );
-        } else {
-            error!("all sources failed, keeping stale data");
         }
+        families_over_limit = dropped.len();
     }
+    let threads = match config.compression_threads {
+        Some(n) if n > 0 => n,
+        _ => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let shards = build_shards(
+        &all_families,
+        layout,
+        config.gzip_min_bytes,
+        threads,
+        &previous.shards,
+        config.track_series_churn,
+    );
+    // The shadow sharding is only measured, never served.
+    let algorithm_stats = match config.shadow_algorithm {
+        Some(shadow) => {
+            let mut algorithms = vec![HashAlgorithm::Jump];
+            if shadow != HashAlgorithm::Jump {
+                algorithms.push(shadow);
+            }
+            compare_algorithms(&all_families, layout.num_shards(), &algorithms)
+        }
+        None => Vec::new(),
+    };
+    let views = view_layouts
+        .iter()
+        .map(|(name, layout)| {
+            let previous = previous.views.get(name).map_or(&[][..], Vec::as_slice);
+            let shards = build_shards(
+                &all_families,
+                layout,
+                config.gzip_min_bytes,
+                threads,
+                previous,
+                config.track_series_churn,
+            );
+            (name.clone(), shards)
+        })
+        .collect();
+    Some(ShardedState {
+        shards,
+        views,
+        last_scrape: Instant::now(),
+        scraped_at: SystemTime::now(),
+        source_status: source_statuses,
+        label_names: label_name_counts(&all_families),
+        algorithm_stats,
+        families_over_limit,
+        all_text: OnceLock::new(),
+    })
 }
 
-type ScrapeResult = (
-    String,
-    Result<(Vec<crate::parser::ParsedFamily>, Duration), (String, Duration)>,
-);
+/// Picks the next cycle period uniformly from `[interval - jitter, interval + jitter]`.
+/// The offset is symmetric, so the average period stays `interval`.
+pub(crate) fn jittered_interval(
+    interval: Duration,
+    jitter: Duration,
+    rng: &mut impl Rng,
+) -> Duration {
+    if jitter.is_zero() {
+        return interval;
+    }
+    let offset = rng.random_range(-jitter.as_secs_f64()..=jitter.as_secs_f64());
+    Duration::from_secs_f64((interval.as_secs_f64() + offset).max(0.0))
+}
 
-async fn scrape_all(client: &Client, sources: &[SourceConfig]) -> Vec<ScrapeResult> {
-    let mut join_set: JoinSet<ScrapeResult> = JoinSet::new();
+/// A source paired with the HTTP client configured for it.
+type ScrapeTarget = (Arc<SourceConfig>, Client);
 
-    for source in sources {
-        let client = client.clone();
-        let url = source.url.clone();
-        let timeout = Duration::from_secs(source.timeout_secs);
-        let headers = source.headers.clone();
-        let extra_labels = source.extra_labels.clone();
+/// Returns a client builder with the global proxy settings applied.
+///
+/// `http_proxy`/`https_proxy` fall back to the `HTTP_PROXY`/`HTTPS_PROXY`
+/// environment variables, and `no_proxy` to `NO_PROXY`, so an unconfigured
+/// proxy behaves exactly like reqwest's default.
+pub(crate) fn client_builder(config: &AppConfig) -> anyhow::Result<ClientBuilder> {
+    let mut builder = Client::builder();
+    let http = config
+        .http_proxy
+        .clone()
+        .or_else(|| proxy_env("HTTP_PROXY"));
+    let https = config
+        .https_proxy
+        .clone()
+        .or_else(|| proxy_env("HTTPS_PROXY"));
+    if http.is_none() && https.is_none() {
+        return Ok(builder);
+    }
 
-        join_set.spawn(async move {
-            let start = Instant::now();
-            let mut req = client.get(&url).timeout(timeout);
-            for (k, v) in &headers {
-                req = req.header(k.as_str(), v.as_str());
-            }
+    let no_proxy = if config.no_proxy.is_empty() {
+        NoProxy::from_env()
+    } else {
+        NoProxy::from_string(&config.no_proxy.join(","))
+    };
+    if let Some(url) = http {
+        let proxy = Proxy::http(&url).with_context(|| format!("invalid http_proxy {url:?}"))?;
+        builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+    }
+    if let Some(url) = https {
+        let proxy = Proxy::https(&url).with_context(|| format!("invalid https_proxy {url:?}"))?;
+        builder = builder.proxy(proxy.no_proxy(no_proxy));
+    }
+    Ok(builder)
+}
+
+/// Reads a proxy environment variable, accepting the lowercase spelling too.
+fn proxy_env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .or_else(|_| std::env::var(name.to_ascii_lowercase()))
+        .ok()
+        .filter(|v| !v.is_empty())
+}
+
+/// Builds a dedicated HTTP client for a source with non-default transport
+/// options (compression, TLS, its own proxy). Returns `None` when the shared
+/// default client will do, which keeps the common case zero-cost.
+///
+/// Client certificates and custom CAs need reqwest's rustls TLS backend.
+pub(crate) fn build_client(
+    config: &AppConfig,
+    source: &SourceConfig,
+) -> anyhow::Result<Option<Client>> {
+    let needs_tls = source.tls_client_cert.is_some()
+        || source.tls_ca_cert.is_some()
+        || source.insecure_skip_verify;
+    if source.request_gzip
+        && !needs_tls
+        && source.proxy.is_none()
+        && source.connect_timeout_secs.is_none()
+    {
+        return Ok(None);
+    }
+
+    let mut builder = match &source.proxy {
+        // Replaces the global proxies and the environment fallback.
+        Some(url) => Client::builder()
+            .proxy(Proxy::all(url).with_context(|| format!("invalid source proxy {url:?}"))?),
+        None => client_builder(config)?,
+    };
+    if !source.request_gzip {
+        builder = builder.no_gzip().no_brotli().no_zstd();
+    }
+    if let Some(secs) = source.connect_timeout_secs {
+        builder = builder.connect_timeout(Duration::from_secs(secs));
+    }
+    if let (Some(cert), Some(key)) = (&source.tls_client_cert, &source.tls_client_key) {
+        let mut pem = std::fs::read(cert)
+            .with_context(|| format!("failed to read tls_client_cert {}", cert.display()))?;
+        pem.extend(
+            std::fs::read(key)
+                .with_context(|| format!("failed to read tls_client_key {}", key.display()))?,
+        );
+        let identity = reqwest::Identity::from_pem(&pem)
+            .context("failed to load tls_client_cert/tls_client_key")?;
+        builder = builder.identity(identity);
+    }
+    if let Some(ca) = &source.tls_ca_cert {
+        let pem = std::fs::read(ca)
+            .with_context(|| format!("failed to read tls_ca_cert {}", ca.display()))?;
+        for cert in reqwest::Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("failed to parse tls_ca_cert {}", ca.display()))?
+        {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if source.insecure_skip_verify {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Sets `Authorization` from `bearer_token_file` or `basic_auth.password_file`.
+///
+/// The files are read on every scrape so rotated secrets take effect without a
+/// restart. An explicit `Authorization` entry in `headers` wins and suppresses
+/// the file-based credentials.
+fn apply_file_auth(
+    req: reqwest::RequestBuilder,
+    source: &SourceConfig,
+) -> anyhow::Result<reqwest::RequestBuilder> {
+    if source
+        .headers
+        .keys()
+        .any(|k| k.eq_ignore_ascii_case("authorization"))
+    {
+        return Ok(req);
+    }
+    if let Some(path) = &source.bearer_token_file {
+        let token = read_secret(path).context("failed to read bearer_token_file")?;
+        return Ok(req.bearer_auth(token));
+    }
+    if let Some(auth) = &source.basic_auth {
+        let password =
+            read_secret(&auth.password_file).context("failed to read basic_auth password_file")?;
+        return Ok(req.basic_auth(&auth.username, Some(password)));
+    }
+    Ok(req)
+}
+
+/// Reads a secret file, trimming the trailing newline most tools add.
+fn read_secret(path: &Path) -> std::io::Result<String> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(content.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+fn unix_millis(t: SystemTime) -> i64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
 
-            let result = async {
-                let body = req.send().await?.text().await?;
-                Ok::<_, reqwest::Error>(body)
+/// Output of one successful source scrape.
+struct SourceScrape {
+    /// `Ok`, or `NoContent` for an empty-status response.
+    outcome: ScrapeOutcome,
+    families: Vec<ParsedFamily>,
+    duration: Duration,
+    /// Part of `duration` spent on the request and body download.
+    fetch: Duration,
+    body_bytes: usize,
+    parse_stats: ParseStats,
+    /// Series dropped by scrape-time limits, by reason.
+    dropped_series: BTreeMap<&'static str, usize>,
+    /// Sample of the families that lost series, by reason.
+    dropped_families: BTreeMap<&'static str, Vec<String>>,
+    /// Samples whose out-of-tolerance timestamp was stripped.
+    stripped_timestamps: usize,
+}
+
+/// A failed source scrape: its classification plus the error for logs.
+struct ScrapeFailure {
+    outcome: ScrapeOutcome,
+    error: String,
+    duration: Duration,
+    fetch: Duration,
+    /// Set when the body was parsed (`Empty`), so garbage-only bodies are visible.
+    parse_stats: ParseStats,
+}
+
+type ScrapeResult = (String, Result<SourceScrape, ScrapeFailure>);
+
+/// Series dropped by the scrape-time stages of one source.
+#[derive(Default)]
+struct Drops {
+    series: BTreeMap<&'static str, usize>,
+    families: BTreeMap<&'static str, Vec<String>>,
+}
+
+impl Drops {
+    /// Runs a dropping `stage` and, if it dropped anything, records the count
+    /// under `reason` with the first few families that lost series.
+    fn track(
+        &mut self,
+        families: &mut Vec<ParsedFamily>,
+        reason: &'static str,
+        stage: impl FnOnce(&mut Vec<ParsedFamily>) -> usize,
+    ) -> usize {
+        let before: Vec<(String, usize)> = families
+            .iter()
+            .map(|f| (f.name.clone(), f.samples.len()))
+            .collect();
+        let dropped = stage(families);
+        if dropped > 0 {
+            let after: HashMap<&str, usize> = families
+                .iter()
+                .map(|f| (f.name.as_str(), f.samples.len()))
+                .collect();
+            let names = before
+                .into_iter()
+                .filter(|(name, len)| after.get(name.as_str()).is_none_or(|a| a < len))
+                .map(|(name, _)| name)
+                .take(DROPPED_FAMILY_SAMPLES)
+                .collect();
+            self.series.insert(reason, dropped);
+            self.families.insert(reason, names);
+        }
+        dropped
+    }
+}
+
+/// Scrapes one source: fetch, then transforms, parsing and the per-source
+/// limits and labels. Shared by the scrape loop and `check-target`.
+async fn scrape_source(
+    client: &Client,
+    source: &SourceConfig,
+    config: &AppConfig,
+) -> Result<SourceScrape, ScrapeFailure> {
+    let timeout = Duration::from_secs(source.timeout_secs);
+    let start = Instant::now();
+    let mut req = client
+        .get(request_url(&source.url).as_ref())
+        .timeout(timeout);
+    // reqwest appends rather than replaces, so the default `User-Agent` is
+    // only added when the source doesn't set its own.
+    if !source
+        .headers
+        .keys()
+        .any(|k| k.eq_ignore_ascii_case("user-agent"))
+    {
+        let agent = config.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+        req = req.header(USER_AGENT, agent);
+    }
+    // Like `User-Agent`, an explicit `Accept` header wins.
+    if source.accept_protobuf
+        && !source
+            .headers
+            .keys()
+            .any(|k| k.eq_ignore_ascii_case("accept"))
+    {
+        req = req.header(ACCEPT, PROTOBUF_ACCEPT);
+    }
+    for (k, v) in &source.headers {
+        req = req.header(k.as_str(), v.replace("{source_url}", &source.url));
+    }
+
+    // Time until the body has fully arrived. Everything after (transforms,
+    // parsing, limits) counts as parse time; a streamed body is parsed while
+    // it downloads, so that share is counted as fetch.
+    let mut fetch = Duration::ZERO;
+    let result = async {
+        let req = apply_file_auth(req, source).map_err(|e| (ScrapeOutcome::Other, e))?;
+        let resp = send(req, source, timeout).await?;
+        let status = resp.status();
+        if status == StatusCode::NO_CONTENT || source.empty_statuses.contains(&status.as_u16()) {
+            fetch = start.elapsed();
+            return Ok((Default::default(), 0, ScrapeOutcome::NoContent));
+        }
+        if !status.is_success() {
+            return Err((
+                ScrapeOutcome::HttpStatus(status.as_u16()),
+                anyhow::anyhow!("upstream returned {status}"),
+            ));
+        }
+        if resp.is_protobuf() {
+            // Decoded into text up front, so transforms and parsing are the
+            // same as for a text body.
+            let body = resp.bytes().await?;
+            fetch = start.elapsed();
+            let decoded = protobuf::decode_to_text(&body).map_err(|e| (ScrapeOutcome::Other, e))?;
+            check_utf8(source, decoded.invalid_utf8)?;
+            let text = apply_transforms(&decoded.text, &source.transforms);
+            let mut parser = FamilyParser::default().strict_values(config.strict_values);
+            text.lines().for_each(|line| parser.push_line(line));
+            let (families, mut stats) = parser.finish();
+            stats.invalid_utf8 = decoded.invalid_utf8;
+            stats.malformed += decoded.invalid_names;
+            return Ok(((families, stats), body.len(), ScrapeOutcome::Ok));
+        }
+        if !source.transforms.is_empty() {
+            // Transforms rewrite the whole body, so it must be buffered.
+            let body = resp.bytes().await?;
+            fetch = start.elapsed();
+            let (text, invalid_utf8) = decode_lossy(&body);
+            check_utf8(source, invalid_utf8)?;
+            let text = apply_transforms(&text, &source.transforms);
+            let mut parser = FamilyParser::default().strict_values(config.strict_values);
+            text.lines().for_each(|line| parser.push_line(line));
+            let (families, mut stats) = parser.finish();
+            stats.invalid_utf8 = invalid_utf8;
+            return Ok(((families, stats), body.len(), ScrapeOutcome::Ok));
+        }
+        // Parse lines as chunks arrive so the full body is never held at
+        // once alongside the per-sample copies.
+        let mut resp = resp;
+        let mut parser = FamilyParser::default().strict_values(config.strict_values);
+        let mut lines = LineSplitter::default();
+        let mut body_bytes = 0;
+        while let Some(chunk) = resp.chunk().await? {
+            body_bytes += chunk.len();
+            lines.push(&chunk, |line| parser.push_line(line));
+        }
+        fetch = start.elapsed();
+        let invalid_utf8 = lines.finish(|line| parser.push_line(line));
+        check_utf8(source, invalid_utf8)?;
+        let (families, mut stats) = parser.finish();
+        stats.invalid_utf8 = invalid_utf8;
+        Ok::<_, (ScrapeOutcome, anyhow::Error)>(((families, stats), body_bytes, ScrapeOutcome::Ok))
+    }
+    .await;
+
+    match result {
+        Ok(((mut families, parse_stats), body_bytes, outcome)) => {
+            let no_content = outcome == ScrapeOutcome::NoContent;
+            if families.is_empty() && !source.allow_empty_source && !no_content {
+                return Err(ScrapeFailure {
+                    outcome: ScrapeOutcome::Empty,
+                    error: "response contained no metric families".to_owned(),
+                    duration: start.elapsed(),
+                    fetch,
+                    parse_stats,
+                });
             }
-            .await;
-
-            match result {
-                Ok(body) => {
-                    let mut families = parse_families(&body);
-                    inject_labels(&mut families, &extra_labels);
-                    let duration = start.elapsed();
-                    (url, Ok((families, duration)))
-                }
-                Err(e) => {
-                    let duration = start.elapsed();
-                    (url, Err((e.to_string(), duration)))
+            let mut drops = Drops::default();
+            if config.drop_self_metrics {
+                drops.track(&mut families, "self_metrics", |f| {
+                    drop_families_with_prefix(f, SELF_METRICS_PREFIX)
+                });
+            }
+            let drop = config.duplicate_labels == DuplicateLabelAction::Drop;
+            let affected = if drop {
+                drops.track(&mut families, "duplicate_labels", |f| {
+                    dedupe_series_labels(f, true)
+                })
+            } else {
+                dedupe_series_labels(&mut families, false)
+            };
+            if affected > 0 {
+                warn!(
+                    source = %redact_url(&source.url),
+                    series = affected,
+                    action = if drop { "dropped" } else { "kept last occurrence" },
+                    "series with duplicate label names"
+                );
+            }
+            // Limit upstream label depth before our own extra_labels are added.
+            if let Some(max) = config.max_labels_per_series {
+                drops.track(&mut families, "max_labels", |f| {
+                    drop_series_over_label_limit(f, max)
+                });
+            }
+            // Before the tolerance check, which may strip the timestamps.
+            if let Some(max_age) = config.drop_stale_samples_older_than_secs {
+                let max_age_ms = i64::try_from(max_age.saturating_mul(1000)).unwrap_or(i64::MAX);
+                let cutoff = unix_millis(SystemTime::now()).saturating_sub(max_age_ms);
+                drops.track(&mut families, "stale_timestamp", |f| {
+                    drop_samples_older_than(f, cutoff)
+                });
+            }
+            let mut stripped_timestamps = 0;
+            if let Some(tolerance) = config.timestamp_tolerance_secs {
+                let now = unix_millis(SystemTime::now());
+                let tolerance = tolerance.saturating_mul(1000);
+                if config.timestamp_out_of_tolerance == TimestampAction::Drop {
+                    drops.track(&mut families, "timestamp_out_of_range", |f| {
+                        enforce_timestamp_tolerance(f, now, tolerance, true)
+                    });
+                } else {
+                    stripped_timestamps =
+                        enforce_timestamp_tolerance(&mut families, now, tolerance, false);
                 }
             }
+            if config.extra_labels.is_empty() {
+                inject_labels(&mut families, &source.extra_labels);
+            } else {
+                let mut labels = config.extra_labels.clone();
+                labels.extend(source.extra_labels.clone());
+                inject_labels(&mut families, &labels);
+            }
+            if config.canonicalize_labels {
+                canonicalize_labels(&mut families);
+            }
+            if source.help_authority {
+                families.iter_mut().for_each(|f| f.help_authority = true);
+            }
+            let duration = start.elapsed();
+            Ok(SourceScrape {
+                outcome,
+                families,
+                duration,
+                fetch,
+                body_bytes,
+                parse_stats,
+                dropped_series: drops.series,
+                dropped_families: drops.families,
+                stripped_timestamps,
+            })
+        }
+        Err((outcome, e)) => {
+            let duration = start.elapsed();
+            Err(ScrapeFailure {
+                outcome,
+                error: format!("{e:#}"),
+                duration,
+                fetch: duration,
+                parse_stats: ParseStats::default(),
+            })
+        }
+    }
+}
+
+/// Scrapes `url` once, as the scrape loop would a `[[sources]]` entry with
+/// default settings, and renders a short report. Errors describe the failure.
+pub async fn check_target(url: &str, timeout_secs: u64) -> anyhow::Result<String> {
+    let config = AppConfig::single_source(SourceConfig::new(url, timeout_secs));
+    let source = &config.sources[0];
+    let client = match build_client(&config, source)? {
+        Some(client) => client,
+        None => client_builder(&config)?.build()?,
+    };
+    let scrape = scrape_source(&client, source, &config)
+        .await
+        .map_err(|f| anyhow::anyhow!("{} ({}): {}", url, f.outcome.kind(), f.error))?;
+
+    let series: usize = scrape.families.iter().map(|f| f.samples.len()).sum();
+    let examples: Vec<&str> = scrape
+        .families
+        .iter()
+        .take(5)
+        .map(|f| f.name.as_str())
+        .collect();
+    let mut out = format!("OK {url} in {} ms\n", scrape.duration.as_millis());
+    out.push_str(&format!("families: {}\n", scrape.families.len()));
+    out.push_str(&format!("series: {series}\n"));
+    out.push_str(&format!("body_bytes: {}\n", scrape.body_bytes));
+    if scrape.parse_stats.malformed > 0 {
+        out.push_str(&format!(
+            "malformed_lines: {}\n",
+            scrape.parse_stats.malformed
+        ));
+    }
+    if scrape.parse_stats.invalid_values > 0 {
+        out.push_str(&format!(
+            "invalid_values: {}\n",
+            scrape.parse_stats.invalid_values
+        ));
+    }
+    if scrape.parse_stats.invalid_utf8 > 0 {
+        out.push_str(&format!(
+            "invalid_utf8: {}\n",
+            scrape.parse_stats.invalid_utf8
+        ));
+    }
+    out.push_str(&format!("examples: {}\n", examples.join(", ")));
+    Ok(out)
+}
+
+/// Fails the scrape over invalid UTF-8 when the source asks for it.
+fn check_utf8(source: &SourceConfig, invalid: usize) -> Result<(), (ScrapeOutcome, anyhow::Error)> {
+    if invalid > 0 && source.on_invalid_utf8 == InvalidUtf8Action::Fail {
+        return Err((
+            ScrapeOutcome::Other,
+            anyhow::anyhow!("body is not valid UTF-8 ({invalid} invalid sequences)"),
+        ));
+    }
+    Ok(())
+}
+
+/// A response from an `http(s)` source, streamed by reqwest, or from a
+/// `unix://` source, read in full before parsing.
+enum Upstream {
+    Http(reqwest::Response),
+    #[cfg(unix)]
+    Buffered(StatusCode, HeaderMap, Option<Bytes>),
+}
+
+impl Upstream {
+    fn status(&self) -> StatusCode {
+        match self {
+            Upstream::Http(resp) => resp.status(),
+            #[cfg(unix)]
+            Upstream::Buffered(status, _, _) => *status,
+        }
+    }
+
+    fn is_protobuf(&self) -> bool {
+        let headers = match self {
+            Upstream::Http(resp) => resp.headers(),
+            #[cfg(unix)]
+            Upstream::Buffered(_, headers, _) => headers,
+        };
+        headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(protobuf::is_protobuf)
+    }
+
+    async fn chunk(&mut self) -> Result<Option<Bytes>, (ScrapeOutcome, anyhow::Error)> {
+        match self {
+            Upstream::Http(resp) => resp
+                .chunk()
+                .await
+                .map_err(|e| (classify(&e), e.without_url().into())),
+            #[cfg(unix)]
+            Upstream::Buffered(_, _, body) => Ok(body.take()),
+        }
+    }
+
+    async fn bytes(self) -> Result<Bytes, (ScrapeOutcome, anyhow::Error)> {
+        match self {
+            Upstream::Http(resp) => resp
+                .bytes()
+                .await
+                .map_err(|e| (classify(&e), e.without_url().into())),
+            #[cfg(unix)]
+            Upstream::Buffered(_, _, body) => Ok(body.unwrap_or_default()),
+        }
+    }
+}
+
+/// The URL reqwest builds the request for. reqwest rejects `unix://`, so such
+/// a source's request, only built for its headers, targets its HTTP path on
+/// `localhost` instead.
+fn request_url(url: &str) -> Cow<'_, str> {
+    #[cfg(unix)]
+    if let Some((_, path)) = unix_socket::parse_unix_url(url) {
+        return Cow::Owned(format!("http://localhost{path}"));
+    }
+    Cow::Borrowed(url)
+}
+
+/// Sends the scrape request, over the source's Unix socket for a `unix://`
+/// URL. Its headers, credentials included, are taken from `req` either way.
+#[cfg_attr(not(unix), allow(unused_variables))]
+async fn send(
+    req: reqwest::RequestBuilder,
+    source: &SourceConfig,
+    timeout: Duration,
+) -> Result<Upstream, (ScrapeOutcome, anyhow::Error)> {
+    #[cfg(unix)]
+    if let Some((socket, path)) = unix_socket::parse_unix_url(&source.url) {
+        let headers = req
+            .build()
+            .map_err(|e| (ScrapeOutcome::Other, e.into()))?
+            .headers()
+            .clone();
+        let connect_timeout = source.connect_timeout_secs.map(Duration::from_secs);
+        let resp = time::timeout(
+            timeout,
+            unix_socket::get(socket, path, headers, connect_timeout),
+        )
+        .await
+        .map_err(|_| {
+            (
+                ScrapeOutcome::Timeout,
+                anyhow::anyhow!("no response within {}s", timeout.as_secs()),
+            )
+        })??;
+        let (parts, body) = resp.into_parts();
+        return Ok(Upstream::Buffered(parts.status, parts.headers, Some(body)));
+    }
+    req.send()
+        .await
+        .map(Upstream::Http)
+        // reqwest puts the URL in its errors, credentials included.
+        .map_err(|e| (classify(&e), e.without_url().into()))
+}
+
+/// Maps a transport error onto the outcome it represents.
+fn classify(e: &reqwest::Error) -> ScrapeOutcome {
+    if e.is_timeout() {
+        ScrapeOutcome::Timeout
+    } else if e.is_connect() {
+        ScrapeOutcome::ConnectError
+    } else {
+        ScrapeOutcome::Other
+    }
+}
+
+async fn scrape_all(targets: &[ScrapeTarget], config: &Arc<AppConfig>) -> Vec<ScrapeResult> {
+    let mut join_set: JoinSet<(usize, ScrapeResult)> = JoinSet::new();
+    let limit = config
+        .max_concurrent_scrapes
+        .filter(|&n| n > 0)
+        .map(|n| Arc::new(Semaphore::new(n)));
+
+    for (i, (source, client)) in targets.iter().enumerate() {
+        let client = client.clone();
+        let source = source.clone();
+        let config = config.clone();
+        let limit = limit.clone();
+
+        join_set.spawn(async move {
+            // Held for the whole scrape; the semaphore is never closed.
+            let _permit = match &limit {
+                Some(sem) => Some(sem.acquire().await.expect("scrape semaphore closed")),
+                None => None,
+            };
+            let url = redact_url(&source.url).into_owned();
+            (i, (url, scrape_source(&client, &source, &config).await))
         });
     }
 
-    let mut results = Vec::with_capacity(sources.len());
+    let mut results = Vec::with_capacity(targets.len());
     while let Some(res) = join_set.join_next().await {
         match res {
             Ok(item) => results.push(item),
             Err(e) => error!("scrape task panicked: {}", e),
         }
     }
-    results
+    // Back in config order, which decides the kept copy of a duplicate series.
+    results.sort_unstable_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
 }
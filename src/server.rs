@@ -1,38 +1,609 @@
-use axum::Router;
-use axum::body::Body;
-use axum::extract::{Path, State};
-use axum::http::{StatusCode, header};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::{ConnectInfo, MatchedPath, Path, Query, Request, State};
+use axum::http::{Extensions, HeaderMap, StatusCode, Version, header};
+use axum::middleware::{self, Next};
 use axum::response::{IntoResponse, Response};
-use axum::routing::get;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::prelude::{BASE64_STANDARD, Engine as _};
+use http_body::{Frame, SizeHint};
+use regex::Regex;
+use serde::Deserialize;
 use serde_json::json;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tower_http::compression::CompressionLayer;
+use tower_http::compression::predicate::{DefaultPredicate, Predicate};
+
+use crate::binary::encode_shard;
+use crate::config::{AppConfig, AuthConfig, is_valid_label_name};
+use crate::hasher::ShardLayout;
+use crate::json::encode_shard_json;
+use crate::metrics::{MetricKind, MetricWriter, Metrics, REQUEST_DURATION_BUCKETS};
+use crate::parser::{escape_label_value, extract_sorted_label_key, parse_families};
+use crate::state::{
+    DROPPED_FAMILY_SAMPLES, Heartbeat, ScrapeOutcome, ShardData, ShardGzip, ShardedState,
+    SharedState, SourceStatus, merge_shard_texts, render_families, reshard_diff,
+};
+use crate::transform::{Transform, apply_transforms};
+
+/// Settings for [`router`] that only shape which endpoints are reachable and
+/// how; the defaults leave every non-admin endpoint open and unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct RouterOptions {
+    /// Mount endpoints that change process state.
+    pub admin_enabled: bool,
+    /// Cap on concurrent shard responses; `None` or `0` is unbounded.
+    pub max_inflight_responses: Option<usize>,
+    /// Shard requests allowed per second per client IP; `None` or `0` is
+    /// unlimited.
+    pub max_requests_per_sec: Option<u32>,
+    /// Basic-auth credentials required on every endpoint but `/livez`.
+    pub auth: Option<AuthConfig>,
+    /// Age past which shard responses are marked stale; `None` never marks.
+    pub stale_after: Option<Duration>,
+    /// Config served, secrets redacted, at `/config`; unmounted when `None`.
+    pub config: Option<Arc<AppConfig>>,
+    /// Path every route is mounted under (`/reaper` serves
+    /// `/reaper/health`); empty mounts at the root.
+    pub route_prefix: String,
+}
 
-use crate::state::SharedState;
+/// `reaper/` and `/reaper/` both become `/reaper`; `/` and empty become empty.
+pub fn normalize_route_prefix(prefix: &str) -> String {
+    let trimmed = prefix.trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{trimmed}")
+    }
+}
 
-pub fn router(state: SharedState, num_shards: u32) -> Router {
-    Router::new()
+pub fn router(
+    state: SharedState,
+    metrics: Arc<Metrics>,
+    layout: Arc<ShardLayout>,
+    views: Arc<BTreeMap<String, ShardLayout>>,
+    heartbeat: Arc<Heartbeat>,
+    options: RouterOptions,
+) -> Router {
+    let num_shards = layout.num_shards();
+    let fingerprints: Arc<Vec<(Option<String>, u64)>> = Arc::new(
+        std::iter::once((None, layout.fingerprint()))
+            .chain(
+                views
+                    .iter()
+                    .map(|(name, layout)| (Some(name.clone()), layout.fingerprint())),
+            )
+            .collect(),
+    );
+    let prefix = normalize_route_prefix(&options.route_prefix);
+    let shards_prefix: Arc<str> = prefix.as_str().into();
+    let handler_metrics = metrics.clone();
+    let reshard_layout = layout.clone();
+    let admin_metrics = metrics.clone();
+    let mut shard_routes = Router::new()
         .route(
             "/metrics/shard/{id}",
-            get(move |state, path| shard_handler(state, path, num_shards)),
+            get(move |state, path, query, headers| {
+                shard_handler(state, path, query, headers, num_shards)
+            }),
+        )
+        .route(
+            "/view/{name}/metrics/shard/{id}",
+            get(move |state, path, query, headers| {
+                view_shard_handler(state, path, query, headers, views)
+            }),
+        )
+        .route(
+            "/metrics/shards/{range}",
+            get(move |state, path| shard_range_handler(state, path, num_shards)),
         )
+        .route("/metrics/all", get(all_shards_handler));
+    if let Some(max_age) = options.stale_after {
+        let state = state.clone();
+        shard_routes = shard_routes.route_layer(middleware::from_fn(move |req, next| {
+            mark_stale(state.clone(), max_age, req, next)
+        }));
+    }
+    // Only shard bodies are large enough to matter; probes and /status stay
+    // reachable under a scrape storm.
+    if let Some(limit) = options.max_inflight_responses.filter(|&n| n > 0) {
+        let permits = Arc::new(Semaphore::new(limit));
+        shard_routes = shard_routes.route_layer(middleware::from_fn(move |req, next| {
+            limit_inflight(permits.clone(), req, next)
+        }));
+    }
+    // Added last so it runs first: over-budget clients are turned away
+    // before they take an in-flight permit.
+    if let Some(rate) = options.max_requests_per_sec.filter(|&n| n > 0) {
+        let limiter = Arc::new(RateLimiter::new(rate));
+        tokio::spawn(prune_idle_clients(Arc::downgrade(&limiter)));
+        shard_routes = shard_routes.route_layer(middleware::from_fn(move |req, next| {
+            rate_limit(limiter.clone(), req, next)
+        }));
+    }
+    let mut app = Router::new()
+        .merge(shard_routes)
         .route("/health", get(health_handler))
+        .route("/readyz", get(health_handler))
+        .route("/livez", get(|| async { "ok" }))
+        .route("/-/healthy", get(move || liveness_handler(heartbeat)))
         .route(
             "/status",
-            get(move |state| status_handler(state, num_shards)),
+            get(move |state, headers| status_handler(state, headers, num_shards)),
+        )
+        .route(
+            "/shards",
+            get(move |state, headers| shards_handler(state, headers, num_shards, shards_prefix)),
         )
         .route(
             "/metrics",
-            get(move |state| self_metrics_handler(state, num_shards)),
+            get(move |state| {
+                self_metrics_handler(state, handler_metrics, num_shards, fingerprints)
+            }),
+        )
+        .route(
+            "/debug/shard",
+            get(move |query, headers| debug_shard_handler(query, headers, layout)),
+        )
+        .route(
+            "/debug/reshard",
+            get(move |state, query| debug_reshard_handler(state, query, reshard_layout)),
+        );
+    if let Some(config) = options.config {
+        app = app.route("/config", get(move || config_handler(config)));
+    }
+    // Endpoints that change process state are only mounted when enabled.
+    if options.admin_enabled {
+        app = app
+            .route(
+                "/debug/reset-metrics",
+                post(move || reset_metrics_handler(admin_metrics)),
+            )
+            .route("/debug/transform", post(debug_transform_handler));
+    }
+    if !prefix.is_empty() {
+        app = Router::new().nest(&prefix, app);
+    }
+    // Route layers see the matched route; unmatched requests are only
+    // counted in `prom_reaper_http_responses_total`.
+    let route_metrics = metrics.clone();
+    let mut app = app
+        .route_layer(middleware::from_fn(move |req, next| {
+            record_request(route_metrics.clone(), req, next)
+        }))
+        .layer(
+            CompressionLayer::new().compress_when(DefaultPredicate::new().and(
+                |_: StatusCode, _: Version, _: &HeaderMap, ext: &Extensions| {
+                    ext.get::<SkipCompression>().is_none()
+                },
+            )),
+        );
+    // Outside the routes so unknown paths are challenged too, and inside
+    // `record_response` so rejected requests are still counted.
+    if let Some(auth) = options.auth {
+        let credentials: Arc<[u8]> = format!("{}:{}", auth.username, auth.password)
+            .into_bytes()
+            .into();
+        let livez: Arc<str> = format!("{prefix}/livez").into();
+        app = app.layer(middleware::from_fn(move |req, next| {
+            require_auth(credentials.clone(), livez.clone(), req, next)
+        }));
+    }
+    app.layer(middleware::from_fn(move |req, next| {
+        record_response(metrics.clone(), req, next)
+    }))
+    .with_state(state)
+}
+
+/// Answers 401 with a basic-auth challenge unless the request carries
+/// `credentials` (`user:password`). `livez` stays open for probes.
+async fn require_auth(
+    credentials: Arc<[u8]>,
+    livez: Arc<str>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, encoded)| BASE64_STANDARD.decode(encoded.trim()).ok());
+    let authorized = presented.is_some_and(|p| constant_time_eq(&p, &credentials));
+    if authorized || req.uri().path() == &*livez {
+        return next.run(req).await;
+    }
+    (
+        StatusCode::UNAUTHORIZED,
+        [(
+            header::WWW_AUTHENTICATE,
+            "Basic realm=\"prom_the_reaper\", charset=\"UTF-8\"",
+        )],
+        "unauthorized",
+    )
+        .into_response()
+}
+
+/// Compares without stopping at the first differing byte, so response
+/// timing doesn't reveal how much of a guessed password was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Answers 503 with `Retry-After` once `max_inflight_responses` responses are
+/// in flight. A permit is held until its response body has been sent.
+async fn limit_inflight(permits: Arc<Semaphore>, req: Request, next: Next) -> Response {
+    let Ok(permit) = permits.try_acquire_owned() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "1")],
+            "too many in-flight responses",
+        )
+            .into_response();
+    };
+    next.run(req).await.map(|inner| {
+        Body::new(PermitBody {
+            inner,
+            _permit: permit,
+        })
+    })
+}
+
+/// Answers 429 with `Retry-After` when the client is over
+/// `max_requests_per_sec`. A 304 gives its token back: conditional hits cost
+/// almost nothing, so pollers that revalidate aren't penalised.
+async fn rate_limit(limiter: Arc<RateLimiter>, req: Request, next: Next) -> Response {
+    let client = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip());
+    if let Err(wait) = limiter.acquire(client) {
+        let secs = (wait.as_secs_f64().ceil() as u64).max(1);
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, secs.to_string())],
+            "rate limit exceeded",
+        )
+            .into_response();
+    }
+    let resp = next.run(req).await;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        limiter.refund(client);
+    }
+    resp
+}
+
+/// Response header set on shard responses served from stale data.
+const STALE_HEADER: &str = "x-prom-reaper-stale";
+
+/// Once the served data is older than `max_age`, sets `X-Prom-Reaper-Stale`
+/// and appends `prom_reaper_data_stale` and `prom_reaper_data_age_seconds` to
+/// text bodies. The marked body changes with every request, so it is served
+/// without an ETag, never as a 304, and compressed on the fly.
+async fn mark_stale(
+    state: SharedState,
+    max_age: Duration,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let age = {
+        let guard = state.load();
+        (!guard.shards.is_empty()).then(|| guard.last_scrape.elapsed())
+    };
+    let Some(age) = age.filter(|&age| age > max_age) else {
+        return next.run(req).await;
+    };
+    let headers = req.headers_mut();
+    headers.remove(header::IF_NONE_MATCH);
+    headers.remove(header::IF_MODIFIED_SINCE);
+    // The pre-compressed body can't be appended to; have the handler serve
+    // plain text and leave compression to the outer layer.
+    headers.remove(header::ACCEPT_ENCODING);
+
+    let resp = next.run(req).await;
+    let is_text = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/plain"));
+    let (mut parts, body) = resp.into_parts();
+    parts
+        .headers
+        .insert(STALE_HEADER, header::HeaderValue::from_static("true"));
+    if parts.status != StatusCode::OK || !is_text {
+        return Response::from_parts(parts, body);
+    }
+    let Ok(text) = axum::body::to_bytes(body, usize::MAX).await else {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed to read shard body",
         )
-        .layer(CompressionLayer::new())
-        .with_state(state)
+            .into_response();
+    };
+    let mut w = MetricWriter::default();
+    w.family(
+        "prom_reaper_data_stale",
+        MetricKind::Gauge,
+        "Whether the served data is older than max_staleness_secs.",
+    );
+    w.gauge("prom_reaper_data_stale", &[], 1u8);
+    w.family(
+        "prom_reaper_data_age_seconds",
+        MetricKind::Gauge,
+        "Seconds since the last successful scrape cycle.",
+    );
+    w.gauge("prom_reaper_data_age_seconds", &[], age.as_secs_f64());
+    let mut marked = String::from_utf8_lossy(&text).into_owned();
+    marked.push_str(&w.finish());
+    parts.headers.remove(header::ETAG);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.extensions.remove::<SkipCompression>();
+    Response::from_parts(parts, Body::from(marked))
+}
+
+/// Per-client buckets kept at most; past it, new clients share the bucket
+/// of clients with an unknown address until idle ones are pruned.
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+/// How often buckets that have refilled completely are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Token buckets holding up to one second's worth of requests, one per
+/// client IP, or a single shared one when the peer address is unknown.
+struct RateLimiter {
+    rate: f64,
+    buckets: Mutex<HashMap<Option<IpAddr>, TokenBucket>>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant, rate: f64) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated = now;
+    }
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The bucket `client` is counted against.
+    fn key(
+        buckets: &HashMap<Option<IpAddr>, TokenBucket>,
+        client: Option<IpAddr>,
+    ) -> Option<IpAddr> {
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&client) {
+            None
+        } else {
+            client
+        }
+    }
+
+    /// Takes a token for `client`, or returns how long until one is available.
+    fn acquire(&self, client: Option<IpAddr>) -> Result<(), Duration> {
+        let now = Instant::now();
+        let rate = self.rate;
+        let mut buckets = self.buckets.lock().unwrap();
+        let key = Self::key(&buckets, client);
+        let bucket = buckets.entry(key).or_insert(TokenBucket {
+            tokens: rate,
+            updated: now,
+        });
+        bucket.refill(now, rate);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    /// Returns a token taken by [`acquire`](Self::acquire).
+    fn refund(&self, client: Option<IpAddr>) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let key = Self::key(&buckets, client);
+        if let Some(bucket) = buckets.get_mut(&key) {
+            bucket.tokens = (bucket.tokens + 1.0).min(self.rate);
+        }
+    }
+
+    /// Drops the buckets that have refilled completely: recreating one gives
+    /// the same answer.
+    fn prune(&self) {
+        let now = Instant::now();
+        let rate = self.rate;
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            bucket.refill(now, rate);
+            bucket.tokens < rate
+        });
+    }
+}
+
+/// Prunes `limiter` every [`PRUNE_INTERVAL`] until the router holding it is
+/// dropped.
+async fn prune_idle_clients(limiter: Weak<RateLimiter>) {
+    let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        match limiter.upgrade() {
+            Some(limiter) => limiter.prune(),
+            None => return,
+        }
+    }
+}
+
+/// Largest frame a stored body is handed to the connection in.
+const BODY_CHUNK_BYTES: usize = 64 * 1024;
+
+/// A stored shard body served in [`BODY_CHUNK_BYTES`] slices of the shared
+/// buffer: nothing is copied, the response owns a reference to the buffer
+/// rather than to the state it came from, and the exact length still goes
+/// out as `Content-Length`.
+struct ChunkedBytes(Bytes);
+
+impl HttpBody for ChunkedBytes {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        if self.0.is_empty() {
+            return Poll::Ready(None);
+        }
+        let len = self.0.len().min(BODY_CHUNK_BYTES);
+        Poll::Ready(Some(Ok(Frame::data(self.0.split_to(len)))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.0.len() as u64)
+    }
+}
+
+/// A response body that releases its in-flight permit when dropped.
+struct PermitBody {
+    inner: Body,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl HttpBody for PermitBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Counts a request to a matched route by status code and times its handler.
+async fn record_request(metrics: Arc<Metrics>, req: Request, next: Next) -> Response {
+    let route = req.extensions().get::<MatchedPath>().cloned();
+    let start = Instant::now();
+    let resp = next.run(req).await;
+    if let Some(route) = route {
+        metrics.record_request(route.as_str(), resp.status(), start.elapsed());
+    }
+    resp
+}
+
+/// Response extension telling the compression middleware to leave the body as is.
+#[derive(Clone, Copy)]
+struct SkipCompression;
+
+/// Counts every response by status code, including 304s and extractor rejections.
+async fn record_response(metrics: Arc<Metrics>, req: Request, next: Next) -> Response {
+    let resp = next.run(req).await;
+    metrics.record_response(resp.status());
+    resp
+}
+
+#[derive(Deserialize)]
+struct ShardQuery {
+    /// Families whose name fully matches this regex are stripped from the response.
+    exclude: Option<String>,
 }
 
 async fn shard_handler(
     State(state): State<SharedState>,
-    Path(id): Path<u32>,
+    Path(raw_id): Path<String>,
+    Query(query): Query<ShardQuery>,
+    headers: HeaderMap,
+    num_shards: u32,
+) -> Response {
+    let snapshot = state.load_full();
+    serve_shard(
+        &snapshot,
+        &snapshot.shards,
+        &raw_id,
+        query,
+        &headers,
+        num_shards,
+    )
+}
+
+/// `/view/{name}/metrics/shard/{id}`: like [`shard_handler`], over a
+/// `[[view]]`'s shards.
+async fn view_shard_handler(
+    State(state): State<SharedState>,
+    Path((name, raw_id)): Path<(String, String)>,
+    Query(query): Query<ShardQuery>,
+    headers: HeaderMap,
+    views: Arc<BTreeMap<String, ShardLayout>>,
+) -> Response {
+    let Some(layout) = views.get(&name) else {
+        return (StatusCode::NOT_FOUND, format!("view {name:?} not found")).into_response();
+    };
+    let snapshot = state.load_full();
+    let shards = snapshot.views.get(&name).map_or(&[][..], Vec::as_slice);
+    serve_shard(
+        &snapshot,
+        shards,
+        &raw_id,
+        query,
+        &headers,
+        layout.num_shards(),
+    )
+}
+
+/// Serves `{id}` as exposition text, or `{id}.bin` in the compact binary
+/// format of [`encode_shard`]. The router can't match a suffix after a path
+/// parameter, so the extension is split off here. `Accept: application/json`
+/// gets the structured form of [`encode_shard_json`] instead of text.
+fn serve_shard(
+    snapshot: &ShardedState,
+    shards: &[ShardData],
+    raw_id: &str,
+    query: ShardQuery,
+    headers: &HeaderMap,
     num_shards: u32,
 ) -> Response {
+    let (id, binary) = match raw_id.strip_suffix(".bin") {
+        Some(id) => (id, true),
+        None => (raw_id, false),
+    };
+    let Ok(id) = id.parse::<u32>() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("invalid shard id {raw_id:?}"),
+        )
+            .into_response();
+    };
     if id >= num_shards {
         return (
             StatusCode::NOT_FOUND,
@@ -41,22 +612,466 @@ async fn shard_handler(
             .into_response();
     }
 
-    let guard = state.load();
-    if guard.shards.is_empty() {
+    let exclude = match query
+        .exclude
+        .as_deref()
+        .map(|p| Regex::new(&format!("^(?:{p})$")))
+    {
+        None => None,
+        Some(Ok(re)) => Some(re),
+        Some(Err(e)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("invalid exclude regex: {e}"),
+            )
+                .into_response();
+        }
+    };
+
+    if shards.is_empty() {
         return (StatusCode::SERVICE_UNAVAILABLE, "metrics not yet available").into_response();
     }
 
-    let text = guard.shards[id as usize].text.clone(); // O(1) ref-count bump
-    axum::http::Response::builder()
+    let shard = &shards[id as usize];
+    let last_modified = httpdate::fmt_http_date(snapshot.scraped_at);
+
+    if binary {
+        if exclude.is_some() {
+            return (StatusCode::BAD_REQUEST, "exclude is not supported for .bin").into_response();
+        }
+        let body = shard
+            .binary
+            .get_or_init(|| encode_shard(std::str::from_utf8(&shard.text).unwrap_or_default()))
+            .clone();
+        return axum::http::Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::LAST_MODIFIED, last_modified)
+            .body(Body::from(body))
+            .unwrap();
+    }
+
+    // Read-time filtering re-renders the shard, so the pre-computed ETag no
+    // longer describes the body; skip conditional handling for it.
+    let filtered = exclude.map(|exclude| {
+        let text = std::str::from_utf8(&shard.text).unwrap_or_default();
+        let families: Vec<_> = parse_families(text)
+            .0
+            .into_iter()
+            .filter(|f| !exclude.is_match(&f.name))
+            .collect();
+        render_families(&families)
+    });
+
+    // The ETag describes the text, so the JSON form is served without one.
+    if accepts(headers, "application/json") {
+        let body = match &filtered {
+            Some(text) => encode_shard_json(text),
+            None => shard
+                .json
+                .get_or_init(|| {
+                    encode_shard_json(std::str::from_utf8(&shard.text).unwrap_or_default())
+                })
+                .clone(),
+        };
+        return axum::http::Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::VARY, "accept")
+            .header(header::LAST_MODIFIED, last_modified)
+            .body(Body::from(body))
+            .unwrap();
+    }
+
+    if let Some(text) = filtered {
+        return axum::http::Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                header::CONTENT_TYPE,
+                "text/plain; version=0.0.4; charset=utf-8",
+            )
+            .header(header::LAST_MODIFIED, last_modified)
+            .body(Body::from(text))
+            .unwrap();
+    }
+
+    // If-None-Match takes precedence over If-Modified-Since (RFC 7232 §6).
+    let not_modified = if headers.contains_key(header::IF_NONE_MATCH) {
+        if_none_match(headers, &shard.etag)
+    } else {
+        not_modified_since(headers, snapshot)
+    };
+    if not_modified {
+        return axum::http::Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, &shard.etag)
+            .header(header::LAST_MODIFIED, last_modified)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    let builder = axum::http::Response::builder()
         .status(StatusCode::OK)
         .header(
             header::CONTENT_TYPE,
             "text/plain; version=0.0.4; charset=utf-8",
         )
-        .body(Body::from(text))
-        .unwrap()
+        .header(header::ETAG, &shard.etag)
+        .header(header::LAST_MODIFIED, last_modified);
+    match &shard.gzip {
+        ShardGzip::Precomputed(gz) if accepts_gzip(headers) => builder
+            .header(header::CONTENT_ENCODING, "gzip")
+            .header(header::VARY, "accept-encoding")
+            .body(Body::new(ChunkedBytes(gz.clone())))
+            .unwrap(),
+        ShardGzip::OnTheFly => builder
+            .body(Body::new(ChunkedBytes(shard.text.clone())))
+            .unwrap(),
+        // Precomputed-but-not-accepted or below the threshold: plain, uncompressed.
+        _ => builder
+            .extension(SkipCompression)
+            .body(Body::new(ChunkedBytes(shard.text.clone())))
+            .unwrap(),
+    }
+}
+
+/// Whether `Accept-Encoding` lists gzip (or `*`) without `q=0`.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let coding = parts.next().unwrap_or_default();
+            let rejected = parts.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (coding.eq_ignore_ascii_case("gzip") || coding == "*") && !rejected
+        })
+}
+
+/// Serves the inclusive shard range `{start}-{end}` as one body, with each
+/// family's HELP/TYPE emitted once. Built per request from the pre-rendered
+/// shard texts.
+async fn shard_range_handler(
+    State(state): State<SharedState>,
+    Path(range): Path<String>,
+    num_shards: u32,
+) -> Response {
+    let Some((start, end)) = range
+        .split_once('-')
+        .and_then(|(s, e)| Some((s.parse::<u32>().ok()?, e.parse::<u32>().ok()?)))
+    else {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("invalid shard range {range:?}, expected {{start}}-{{end}}"),
+        )
+            .into_response();
+    };
+    if start > end || end >= num_shards {
+        return (
+            StatusCode::NOT_FOUND,
+            format!(
+                "shard range {}-{} not found, valid range is 0..{}",
+                start, end, num_shards
+            ),
+        )
+            .into_response();
+    }
+
+    let snapshot = state.load_full();
+    if snapshot.shards.is_empty() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "metrics not yet available").into_response();
+    }
+
+    let body = merge_shard_texts(&snapshot.shards[start as usize..=end as usize]);
+    (
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/plain; version=0.0.4; charset=utf-8".to_owned(),
+            ),
+            (
+                header::LAST_MODIFIED,
+                httpdate::fmt_http_date(snapshot.scraped_at),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Serves every shard as one body, each family with a single HELP/TYPE
+/// header, for downstreams that don't shard. Merged once per scrape cycle,
+/// on the first request.
+async fn all_shards_handler(State(state): State<SharedState>) -> Response {
+    let snapshot = state.load_full();
+    if snapshot.shards.is_empty() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "metrics not yet available").into_response();
+    }
+
+    let body = snapshot
+        .all_text
+        .get_or_init(|| Bytes::from(merge_shard_texts(&snapshot.shards)))
+        .clone();
+    let body = Body::new(ChunkedBytes(body));
+    (
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/plain; version=0.0.4; charset=utf-8".to_owned(),
+            ),
+            (
+                header::LAST_MODIFIED,
+                httpdate::fmt_http_date(snapshot.scraped_at),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Returns true when `If-Modified-Since` is at or after the scrape that
+/// produced `state`. HTTP dates have one-second resolution, so the scrape
+/// time is truncated before comparing.
+fn not_modified_since(headers: &HeaderMap, state: &ShardedState) -> bool {
+    let Some(since) = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+    else {
+        return false;
+    };
+    let scraped_secs = state
+        .scraped_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    since >= UNIX_EPOCH + Duration::from_secs(scraped_secs)
+}
+
+fn not_modified_response(state: &ShardedState) -> Response {
+    (
+        StatusCode::NOT_MODIFIED,
+        [(
+            header::LAST_MODIFIED,
+            httpdate::fmt_http_date(state.scraped_at),
+        )],
+    )
+        .into_response()
+}
+
+/// Returns true when the request's `If-None-Match` lists `etag` (or `*`).
+/// Weak validators (`W/"..."`) are compared by their opaque tag.
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+#[derive(Deserialize)]
+struct DebugShardQuery {
+    metric: String,
+    #[serde(default)]
+    labels: String,
 }
 
+/// Resolves which shard owns a series without touching scraped state.
+///
+/// `labels` is a comma-separated list of `name=value` pairs; values may be
+/// quoted (`path="/a,b"`) to include commas. The canonical key is computed by
+/// the same `extract_sorted_label_key` that `build_shards` uses.
+async fn debug_shard_handler(
+    Query(q): Query<DebugShardQuery>,
+    headers: HeaderMap,
+    layout: Arc<ShardLayout>,
+) -> Response {
+    if q.metric.is_empty() {
+        return (StatusCode::BAD_REQUEST, "missing `metric` parameter").into_response();
+    }
+
+    let pairs = match parse_debug_labels(&q.labels) {
+        Ok(pairs) => pairs,
+        Err(msg) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+    };
+
+    let mut line = q.metric.clone();
+    if !pairs.is_empty() {
+        let rendered: Vec<String> = pairs
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+            .collect();
+        line.push('{');
+        line.push_str(&rendered.join(","));
+        line.push('}');
+    }
+    let canonical_key = extract_sorted_label_key(&line);
+    let pinned = layout.pinned_shard(&q.metric);
+    let shard = pinned.unwrap_or_else(|| layout.assign_line(&q.metric, &line, &canonical_key));
+
+    let body = json!({
+        "metric": q.metric,
+        "canonical_key": canonical_key,
+        "shard": shard,
+        "pinned": pinned.is_some(),
+        "num_shards": layout.num_shards(),
+    });
+    let (content_type, body) = negotiated_body(&headers, &body);
+    (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], body).into_response()
+}
+
+/// Encodes a JSON-shaped response body as MessagePack for clients sending
+/// `Accept: application/msgpack`, and as JSON otherwise.
+fn negotiated_body(headers: &HeaderMap, body: &serde_json::Value) -> (&'static str, Vec<u8>) {
+    if accepts(headers, "application/msgpack") {
+        let encoded = rmp_serde::to_vec_named(body).expect("JSON values always encode");
+        ("application/msgpack", encoded)
+    } else {
+        ("application/json", body.to_string().into_bytes())
+    }
+}
+
+/// Whether `Accept` explicitly lists `media_type` (wildcards don't count).
+fn accepts(headers: &HeaderMap, media_type: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| {
+            item.split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case(media_type)
+        })
+}
+
+/// Parses `a=1,b="x,y"` into `[("a", "1"), ("b", "x,y")]`.
+fn parse_debug_labels(input: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    if input.trim().is_empty() {
+        return Ok(pairs);
+    }
+
+    let mut in_quotes = false;
+    let mut start = 0;
+    let mut raw_pairs = Vec::new();
+    for (i, ch) in input.char_indices() {
+        match ch {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                raw_pairs.push(&input[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if in_quotes {
+        return Err(format!("unterminated quote in labels: {input:?}"));
+    }
+    raw_pairs.push(&input[start..]);
+
+    for raw in raw_pairs {
+        let raw = raw.trim();
+        let Some((name, value)) = raw.split_once('=') else {
+            return Err(format!("label {raw:?} is not in name=value form"));
+        };
+        let name = name.trim();
+        if !is_valid_label_name(name) {
+            return Err(format!("{name:?} is not a valid Prometheus label name"));
+        }
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        pairs.push((name.to_owned(), value.to_owned()));
+    }
+    Ok(pairs)
+}
+
+/// Largest shard count `/debug/reshard` projects; the projection allocates
+/// per proposed shard, so the query must not size it freely.
+const MAX_RESHARD_SHARDS: u32 = 1024;
+
+#[derive(Deserialize)]
+struct DebugReshardQuery {
+    num_shards: u32,
+}
+
+/// Projects the effect of changing `num_shards` on the currently served
+/// series under the served layout (weights, pins, hash labels): moved
+/// fraction and per-shard series/byte deltas. Read-only.
+async fn debug_reshard_handler(
+    State(state): State<SharedState>,
+    Query(q): Query<DebugReshardQuery>,
+    layout: Arc<ShardLayout>,
+) -> Response {
+    if q.num_shards == 0 {
+        return (StatusCode::BAD_REQUEST, "num_shards must be greater than 0").into_response();
+    }
+    if q.num_shards > MAX_RESHARD_SHARDS {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("num_shards must be at most {MAX_RESHARD_SHARDS}"),
+        )
+            .into_response();
+    }
+    let snapshot = state.load_full();
+    if snapshot.shards.is_empty() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "no data yet").into_response();
+    }
+
+    let diff = match reshard_diff(&snapshot.shards, &layout, q.num_shards) {
+        Ok(diff) => diff,
+        Err(msg) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+    };
+    let shards: Vec<_> = diff
+        .shards
+        .iter()
+        .enumerate()
+        .map(|(i, d)| {
+            json!({
+                "id": i,
+                "current_series": d.current_series,
+                "projected_series": d.projected_series,
+                "series_delta": d.projected_series as i64 - d.current_series as i64,
+                "current_bytes": d.current_bytes,
+                "projected_bytes": d.projected_bytes,
+                "bytes_delta": d.projected_bytes as i64 - d.current_bytes as i64,
+            })
+        })
+        .collect();
+
+    let body = json!({
+        "current_num_shards": snapshot.shards.len(),
+        "proposed_num_shards": q.num_shards,
+        "total_series": diff.total_series,
+        "moved_series": diff.moved_series,
+        "moved_fraction": diff.moved_fraction(),
+        "shards": shards,
+    });
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+/// Readiness, served at `/health` and `/readyz`: 503 until the first scrape
+/// has produced data. `/livez` answers 200 whenever the process is serving.
 async fn health_handler(State(state): State<SharedState>) -> Response {
     let guard = state.load();
     if guard.shards.is_empty() {
@@ -66,133 +1081,622 @@ async fn health_handler(State(state): State<SharedState>) -> Response {
     }
 }
 
-async fn status_handler(State(state): State<SharedState>, num_shards: u32) -> Response {
-    let guard = state.load();
-    if guard.shards.is_empty() {
+/// Zeroes the cumulative self-metrics counters. Gauges are computed from the
+/// current state on every `/metrics` request and are unaffected.
+async fn reset_metrics_handler(metrics: Arc<Metrics>) -> Response {
+    metrics.reset();
+    (StatusCode::OK, "metrics reset").into_response()
+}
+
+/// Request body of `POST /debug/transform`.
+#[derive(Deserialize)]
+struct TransformPreview {
+    /// Exposition text standing in for an upstream body.
+    input: String,
+    /// Same shape as a source's `transforms`.
+    #[serde(default)]
+    transforms: Vec<Transform>,
+}
+
+/// Runs a transform pipeline over posted text the way a scrape runs a
+/// source's, so rules can be tried out before deploying them.
+async fn debug_transform_handler(Json(preview): Json<TransformPreview>) -> Response {
+    let out = apply_transforms(&preview.input, &preview.transforms).into_owned();
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        out,
+    )
+        .into_response()
+}
+
+/// Liveness: 503 once the scrape loop has stopped beating, so orchestrators
+/// restart a wedged process. Unlike `/health`, it does not wait for data.
+async fn liveness_handler(heartbeat: Arc<Heartbeat>) -> Response {
+    if heartbeat.is_stale() {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "scrape loop stalled, last heartbeat {:.1}s ago",
+                heartbeat.age().as_secs_f64()
+            ),
+        )
+            .into_response()
+    } else {
+        (StatusCode::OK, "ok").into_response()
+    }
+}
+
+/// The effective config: file values with env overrides and defaults
+/// applied. Passwords, header values and URL credentials are redacted.
+async fn config_handler(config: Arc<AppConfig>) -> Response {
+    Json(config.as_ref()).into_response()
+}
+
+async fn status_handler(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    num_shards: u32,
+) -> Response {
+    let snapshot = state.load_full();
+    if snapshot.shards.is_empty() {
         return (StatusCode::SERVICE_UNAVAILABLE, "no data yet").into_response();
     }
+    if not_modified_since(&headers, &snapshot) {
+        return not_modified_response(&snapshot);
+    }
 
-    let shards: Vec<_> = guard
+    let shards: Vec<_> = snapshot
         .shards
         .iter()
         .enumerate()
-        .map(|(i, s)| {
-            json!({
-                "id": i,
-                "size_bytes": s.text.len(),
-                "families": s.families_count,
-                "series": s.series_count,
-            })
-        })
+        .map(|(i, s)| shard_summary(i, s))
         .collect();
 
-    let sources: Vec<_> = guard
+    let sources: Vec<_> = snapshot
         .source_status
         .iter()
         .map(|s| {
+            let http_status = match s.outcome {
+                ScrapeOutcome::HttpStatus(code) => Some(code),
+                _ => None,
+            };
             json!({
                 "url": s.url,
                 "success": s.success,
+                "health": s.outcome.health(),
+                "outcome": s.outcome.kind(),
+                "http_status": http_status,
                 "duration_ms": s.duration.as_millis() as u64,
+                "fetch_ms": s.fetch_duration.as_millis() as u64,
+                "parse_ms": s.parse_duration().as_millis() as u64,
                 "metric_families": s.metric_families,
+                "body_bytes": s.body_bytes,
+                "series": s.series_count,
+                "parse": {
+                    "lines": s.parse_stats.lines,
+                    "samples": s.parse_stats.samples,
+                    "comments": s.parse_stats.comments,
+                    "malformed": s.parse_stats.malformed,
+                    "invalid_utf8": s.parse_stats.invalid_utf8,
+                    "invalid_values": s.parse_stats.invalid_values,
+                },
+                "dropped_series": s.dropped_series,
+                "stripped_timestamps": s.stripped_timestamps,
             })
         })
         .collect();
 
+    // Debugging aid: which families each drop stage removed series from,
+    // merged across sources and bounded per reason.
+    let mut dropped_samples: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for src in &snapshot.source_status {
+        for (reason, names) in &src.dropped_families {
+            dropped_samples
+                .entry(reason)
+                .or_default()
+                .extend(names.iter().map(String::as_str));
+        }
+    }
+    let dropped_samples: BTreeMap<_, Vec<_>> = dropped_samples
+        .into_iter()
+        .map(|(reason, names)| {
+            (
+                reason,
+                names.into_iter().take(DROPPED_FAMILY_SAMPLES).collect(),
+            )
+        })
+        .collect();
+
     let body = json!({
         "num_shards": num_shards,
-        "last_scrape_ago_secs": guard.last_scrape.elapsed().as_secs_f64(),
+        "last_scrape_ago_secs": snapshot.last_scrape.elapsed().as_secs_f64(),
         "sources": sources,
         "shards": shards,
+        "distribution": series_distribution(&snapshot.shards),
+        "dropped_samples": dropped_samples,
+        "families_over_limit": snapshot.families_over_limit,
     });
 
+    let (content_type, body) = negotiated_body(&headers, &body);
     (
         StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/json")],
-        body.to_string(),
+        [
+            (header::CONTENT_TYPE, content_type.to_owned()),
+            (
+                header::LAST_MODIFIED,
+                httpdate::fmt_http_date(snapshot.scraped_at),
+            ),
+        ],
+        body,
     )
         .into_response()
 }
 
-async fn self_metrics_handler(State(state): State<SharedState>, num_shards: u32) -> Response {
-    let guard = state.load();
-    let mut out = String::new();
-
-    // last scrape age
-    out.push_str("# HELP prom_reaper_last_scrape_age_seconds Seconds since the last successful scrape cycle.\n");
-    out.push_str("# TYPE prom_reaper_last_scrape_age_seconds gauge\n");
-    if guard.shards.is_empty() {
-        out.push_str("prom_reaper_last_scrape_age_seconds NaN\n");
-    } else {
-        out.push_str(&format!(
-            "prom_reaper_last_scrape_age_seconds {:.3}\n",
-            guard.last_scrape.elapsed().as_secs_f64()
-        ));
+/// `/shards`: the shard count and where to fetch each shard, with the sizes
+/// from `/status`, for tools that configure downstream scrapers. Paths
+/// include `route_prefix`.
+async fn shards_handler(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    num_shards: u32,
+    route_prefix: Arc<str>,
+) -> Response {
+    let snapshot = state.load_full();
+    if snapshot.shards.is_empty() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "no data yet").into_response();
+    }
+    if not_modified_since(&headers, &snapshot) {
+        return not_modified_response(&snapshot);
     }
 
+    let shards: Vec<_> = snapshot
+        .shards
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let mut entry = shard_summary(i, s);
+            entry["path"] = json!(format!("{route_prefix}/metrics/shard/{i}"));
+            entry
+        })
+        .collect();
+    let body = json!({
+        "num_shards": num_shards,
+        "shards": shards,
+    });
+
+    let (content_type, body) = negotiated_body(&headers, &body);
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type.to_owned()),
+            (
+                header::LAST_MODIFIED,
+                httpdate::fmt_http_date(snapshot.scraped_at),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Per-shard sizes as reported by `/status` and `/shards`.
+fn shard_summary(id: usize, shard: &ShardData) -> serde_json::Value {
+    json!({
+        "id": id,
+        "size_bytes": shard.text.len(),
+        "families": shard.families_count,
+        "series": shard.series_count,
+    })
+}
+
+/// Balance of series across shards: min/max/mean, population stddev and
+/// `balance_ratio` (max over mean, 1.0 when perfectly even or empty).
+fn series_distribution(shards: &[ShardData]) -> serde_json::Value {
+    let counts = || shards.iter().map(|s| s.series_count as f64);
+    let n = shards.len() as f64;
+    let mean = counts().sum::<f64>() / n;
+    let max = counts().fold(0.0, f64::max);
+    let min = counts().fold(f64::INFINITY, f64::min);
+    let variance = counts().map(|c| (c - mean).powi(2)).sum::<f64>() / n;
+    json!({
+        "series_min": min,
+        "series_max": max,
+        "series_mean": mean,
+        "series_stddev": variance.sqrt(),
+        "balance_ratio": if mean > 0.0 { max / mean } else { 1.0 },
+    })
+}
+
+/// Label names listed individually in `prom_reaper_label_name_series`.
+const TOP_LABEL_NAMES: usize = 10;
+
+/// Served without `Last-Modified` or conditional GET: the request counters
+/// and scrape age change on every request, not only with a new scrape.
+async fn self_metrics_handler(
+    State(state): State<SharedState>,
+    metrics: Arc<Metrics>,
+    num_shards: u32,
+    fingerprints: Arc<Vec<(Option<String>, u64)>>,
+) -> Response {
+    use MetricKind::{Counter, Gauge, Histogram};
+
+    let snapshot = state.load_full();
+    let has_data = !snapshot.shards.is_empty();
+    let mut w = MetricWriter::default();
+
+    w.family(
+        "prom_reaper_last_scrape_age_seconds",
+        Gauge,
+        "Seconds since the last successful scrape cycle.",
+    );
+    let age = match has_data {
+        true => snapshot.last_scrape.elapsed().as_secs_f64(),
+        false => f64::NAN,
+    };
+    w.gauge("prom_reaper_last_scrape_age_seconds", &[], age);
+
     // per-shard series and families
-    out.push_str("# HELP prom_reaper_shard_series Number of time series in a shard.\n");
-    out.push_str("# TYPE prom_reaper_shard_series gauge\n");
-    for (i, shard) in guard.shards.iter().enumerate() {
-        out.push_str(&format!(
-            "prom_reaper_shard_series{{shard=\"{}\"}} {}\n",
-            i, shard.series_count
-        ));
+    let shard_ids: Vec<String> = (0..snapshot.shards.len()).map(|i| i.to_string()).collect();
+    w.family(
+        "prom_reaper_shard_series",
+        Gauge,
+        "Number of time series in a shard.",
+    );
+    for (id, shard) in shard_ids.iter().zip(&snapshot.shards) {
+        w.gauge(
+            "prom_reaper_shard_series",
+            &[("shard", id)],
+            shard.series_count,
+        );
     }
 
-    out.push_str("# HELP prom_reaper_shard_families Number of metric families in a shard.\n");
-    out.push_str("# TYPE prom_reaper_shard_families gauge\n");
-    for (i, shard) in guard.shards.iter().enumerate() {
-        out.push_str(&format!(
-            "prom_reaper_shard_families{{shard=\"{}\"}} {}\n",
-            i, shard.families_count
-        ));
+    w.family(
+        "prom_reaper_shard_families",
+        Gauge,
+        "Number of metric families in a shard.",
+    );
+    for (id, shard) in shard_ids.iter().zip(&snapshot.shards) {
+        w.gauge(
+            "prom_reaper_shard_families",
+            &[("shard", id)],
+            shard.families_count,
+        );
     }
 
-    out.push_str(
-        "# HELP prom_reaper_shard_size_bytes Size of a shard's uncompressed text in bytes.\n",
+    w.family(
+        "prom_reaper_shard_size_bytes",
+        Gauge,
+        "Size of a shard's uncompressed text in bytes.",
     );
-    out.push_str("# TYPE prom_reaper_shard_size_bytes gauge\n");
-    for (i, shard) in guard.shards.iter().enumerate() {
-        out.push_str(&format!(
-            "prom_reaper_shard_size_bytes{{shard=\"{}\"}} {}\n",
-            i,
-            shard.text.len()
-        ));
+    for (id, shard) in shard_ids.iter().zip(&snapshot.shards) {
+        w.gauge(
+            "prom_reaper_shard_size_bytes",
+            &[("shard", id)],
+            shard.text.len(),
+        );
+    }
+
+    if snapshot.shards.iter().any(|s| s.churn.is_some()) {
+        w.family(
+            "prom_reaper_shard_series_added",
+            Gauge,
+            "Series in a shard that were not in it the previous cycle.",
+        );
+        for (id, shard) in shard_ids.iter().zip(&snapshot.shards) {
+            if let Some(churn) = shard.churn {
+                w.gauge(
+                    "prom_reaper_shard_series_added",
+                    &[("shard", id)],
+                    churn.added,
+                );
+            }
+        }
+        w.family(
+            "prom_reaper_shard_series_removed",
+            Gauge,
+            "Series in a shard the previous cycle that are no longer in it.",
+        );
+        for (id, shard) in shard_ids.iter().zip(&snapshot.shards) {
+            if let Some(churn) = shard.churn {
+                w.gauge(
+                    "prom_reaper_shard_series_removed",
+                    &[("shard", id)],
+                    churn.removed,
+                );
+            }
+        }
+    }
+
+    w.family(
+        "prom_reaper_shard_total_bytes",
+        Gauge,
+        "Size of all shards' uncompressed text in bytes.",
+    );
+    let total_bytes: usize = snapshot.shards.iter().map(|s| s.text.len()).sum();
+    w.gauge("prom_reaper_shard_total_bytes", &[], total_bytes);
+
+    w.family(
+        "prom_reaper_allocated_bytes",
+        Gauge,
+        "Memory committed by the allocator (mimalloc).",
+    );
+    let allocated = Metrics::allocated_bytes().map_or(f64::NAN, |bytes| bytes as f64);
+    w.gauge("prom_reaper_allocated_bytes", &[], allocated);
+
+    w.family(
+        "prom_reaper_families_over_limit",
+        Gauge,
+        "Families dropped by max_families in the last scrape cycle.",
+    );
+    w.gauge(
+        "prom_reaper_families_over_limit",
+        &[],
+        snapshot.families_over_limit,
+    );
+
+    w.family(
+        "prom_reaper_distinct_label_names",
+        Gauge,
+        "Distinct label names across all served series.",
+    );
+    w.gauge(
+        "prom_reaper_distinct_label_names",
+        &[],
+        snapshot.label_names.len(),
+    );
+
+    w.family(
+        "prom_reaper_label_name_series",
+        Gauge,
+        &format!("Series carrying a label name, for the {TOP_LABEL_NAMES} most common names."),
+    );
+    let mut top: Vec<(&String, &usize)> = snapshot.label_names.iter().collect();
+    top.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    for (name, &count) in top.into_iter().take(TOP_LABEL_NAMES) {
+        w.gauge("prom_reaper_label_name_series", &[("label", name)], count);
+    }
+
+    if !snapshot.algorithm_stats.is_empty() {
+        w.family(
+            "prom_reaper_shadow_balance_cv",
+            Gauge,
+            "Coefficient of variation of series per shard under each hashing algorithm, over equal shards (jump is served, others are shadow_algorithm).",
+        );
+        for stats in &snapshot.algorithm_stats {
+            let labels = [("algorithm", stats.algorithm.name())];
+            w.gauge("prom_reaper_shadow_balance_cv", &labels, stats.balance_cv);
+        }
+        w.family(
+            "prom_reaper_shadow_resize_moved_ratio",
+            Gauge,
+            "Fraction of series that would change shard if num_shards grew by one, per hashing algorithm.",
+        );
+        for stats in &snapshot.algorithm_stats {
+            let labels = [("algorithm", stats.algorithm.name())];
+            w.gauge(
+                "prom_reaper_shadow_resize_moved_ratio",
+                &labels,
+                stats.resize_moved_ratio,
+            );
+        }
     }
 
     // per-source scrape status
-    out.push_str("# HELP prom_reaper_source_up Whether the last scrape of a source succeeded (1 = success, 0 = failure).\n");
-    out.push_str("# TYPE prom_reaper_source_up gauge\n");
-    for src in &guard.source_status {
-        out.push_str(&format!(
-            "prom_reaper_source_up{{url=\"{}\"}} {}\n",
-            src.url,
-            if src.success { 1 } else { 0 }
-        ));
-    }
-
-    out.push_str("# HELP prom_reaper_source_scrape_duration_seconds Duration of the last scrape for a source.\n");
-    out.push_str("# TYPE prom_reaper_source_scrape_duration_seconds gauge\n");
-    for src in &guard.source_status {
-        out.push_str(&format!(
-            "prom_reaper_source_scrape_duration_seconds{{url=\"{}\"}} {:.3}\n",
-            src.url,
-            src.duration.as_secs_f64()
-        ));
-    }
-
-    out.push_str("# HELP prom_reaper_num_shards Configured number of shards.\n");
-    out.push_str("# TYPE prom_reaper_num_shards gauge\n");
-    out.push_str(&format!("prom_reaper_num_shards {num_shards}\n"));
+    w.family(
+        "prom_reaper_source_up",
+        Gauge,
+        "Whether the last scrape of a source succeeded (1 = success, 0 = failure).",
+    );
+    for src in &snapshot.source_status {
+        w.gauge(
+            "prom_reaper_source_up",
+            &[("url", &src.url)],
+            src.success as u8,
+        );
+    }
+
+    w.family(
+        "prom_reaper_source_last_error",
+        Gauge,
+        "Set for sources whose last scrape failed, labelled by failure kind.",
+    );
+    for src in snapshot.source_status.iter().filter(|s| !s.success) {
+        let labels = [("url", src.url.as_str()), ("kind", src.outcome.kind())];
+        w.gauge("prom_reaper_source_last_error", &labels, 1u8);
+    }
+
+    w.family(
+        "prom_reaper_source_scrape_duration_seconds",
+        Gauge,
+        "Duration of the last scrape for a source.",
+    );
+    for src in &snapshot.source_status {
+        w.gauge(
+            "prom_reaper_source_scrape_duration_seconds",
+            &[("url", &src.url)],
+            src.duration.as_secs_f64(),
+        );
+    }
+
+    // Per-source counts labelled by url alone.
+    let per_source = |w: &mut MetricWriter, name, help, value: fn(&SourceStatus) -> usize| {
+        w.family(name, Gauge, help);
+        for src in &snapshot.source_status {
+            w.gauge(name, &[("url", &src.url)], value(src));
+        }
+    };
+    per_source(
+        &mut w,
+        "prom_reaper_source_body_bytes",
+        "Response body size of the last scrape of a source.",
+        |s| s.body_bytes,
+    );
+    per_source(
+        &mut w,
+        "prom_reaper_source_series",
+        "Series contributed by a source in the last scrape.",
+        |s| s.series_count,
+    );
+    per_source(
+        &mut w,
+        "prom_reaper_source_parse_skipped",
+        "Malformed lines skipped while parsing the last scrape of a source.",
+        |s| s.parse_stats.malformed,
+    );
+    per_source(
+        &mut w,
+        "prom_reaper_source_invalid_values",
+        "Samples skipped by strict_values in the last scrape of a source.",
+        |s| s.parse_stats.invalid_values,
+    );
+    per_source(
+        &mut w,
+        "prom_reaper_source_invalid_utf8",
+        "Invalid UTF-8 sequences replaced with U+FFFD in the last scrape of a source.",
+        |s| s.parse_stats.invalid_utf8,
+    );
+
+    w.family(
+        "prom_reaper_source_dropped_series",
+        Gauge,
+        "Series dropped during the last scrape of a source, by reason.",
+    );
+    for src in &snapshot.source_status {
+        for (reason, &count) in &src.dropped_series {
+            let labels = [("url", src.url.as_str()), ("reason", reason)];
+            w.gauge("prom_reaper_source_dropped_series", &labels, count);
+        }
+    }
 
+    per_source(
+        &mut w,
+        "prom_reaper_source_stripped_timestamps",
+        "Samples whose out-of-tolerance timestamp was stripped during the last scrape of a source.",
+        |s| s.stripped_timestamps,
+    );
+
+    w.family(
+        "prom_reaper_num_shards",
+        Gauge,
+        "Configured number of shards.",
+    );
+    w.gauge("prom_reaper_num_shards", &[], num_shards);
+
+    w.family(
+        "prom_reaper_shard_layout_info",
+        Gauge,
+        "Fingerprint of the shard layout (weights, pins, seed, shard_by, hash_only_labels); a new value means series moved between shards.",
+    );
+    for (view, fingerprint) in fingerprints.iter() {
+        let fingerprint = format!("{fingerprint:016x}");
+        let mut labels = Vec::with_capacity(2);
+        if let Some(view) = view {
+            labels.push(("view", view.as_str()));
+        }
+        labels.push(("fingerprint", &fingerprint));
+        w.gauge("prom_reaper_shard_layout_info", &labels, 1u8);
+    }
+
+    w.family(
+        "prom_reaper_config_file_changed",
+        Gauge,
+        "1 when the config file on disk differs from the one loaded at startup.",
+    );
+    w.gauge(
+        "prom_reaper_config_file_changed",
+        &[],
+        metrics.config_file_changed() as u8,
+    );
+
+    w.family(
+        "prom_reaper_http_responses_total",
+        Counter,
+        "HTTP responses served by the proxy, by status code.",
+    );
+    for (code, count) in metrics.http_responses() {
+        let code = code.to_string();
+        w.counter(
+            "prom_reaper_http_responses_total",
+            &[("code", &code)],
+            count,
+        );
+    }
+
+    let routes = metrics.http_requests();
+    w.family(
+        "prom_reaper_http_requests_total",
+        Counter,
+        "HTTP requests served by the proxy, by matched route and status code.",
+    );
+    for (route, stats) in &routes {
+        for (code, &count) in &stats.codes {
+            let code = code.to_string();
+            let labels = [("route", route.as_str()), ("code", &code)];
+            w.counter("prom_reaper_http_requests_total", &labels, count);
+        }
+    }
+    w.family(
+        "prom_reaper_http_request_duration_seconds",
+        Histogram,
+        "Handler duration of HTTP requests, by matched route.",
+    );
+    for (route, stats) in &routes {
+        let cumulative: Vec<u64> = stats
+            .buckets
+            .iter()
+            .scan(0, |total, &n| {
+                *total += n;
+                Some(*total)
+            })
+            .collect();
+        w.histogram(
+            "prom_reaper_http_request_duration_seconds",
+            &[("route", route)],
+            &REQUEST_DURATION_BUCKETS,
+            &cumulative,
+            stats.duration_sum.as_secs_f64(),
+        );
+    }
     (
         StatusCode::OK,
         [(
             header::CONTENT_TYPE,
             "text/plain; version=0.0.4; charset=utf-8",
         )],
-        out,
+        w.finish(),
     )
         .into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_tracks_a_bounded_number_of_clients() {
+        let limiter = RateLimiter::new(1);
+        let cap = MAX_TRACKED_CLIENTS as u32;
+        for i in 0..cap + 1000 {
+            let client = IpAddr::from(i.to_be_bytes());
+            // The first client past the cap takes the shared bucket's token.
+            assert_eq!(
+                limiter.acquire(Some(client)).is_ok(),
+                i <= cap,
+                "client {i}"
+            );
+        }
+        // Every client past the cap shares the bucket of unknown peers.
+        let tracked = limiter.buckets.lock().unwrap().len();
+        assert_eq!(tracked, MAX_TRACKED_CLIENTS + 1);
+        assert!(limiter.acquire(None).is_err());
+
+        // Nothing has refilled yet, so pruning keeps every bucket...
+        limiter.prune();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), tracked);
+        // ...until the buckets are full again.
+        std::thread::sleep(Duration::from_millis(1100));
+        limiter.prune();
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+}
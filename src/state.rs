@@ -1,19 +1,78 @@
-use std::collections::HashSet;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use arc_swap::ArcSwap;
 use bytes::Bytes;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use xxhash_rust::xxh3::xxh3_64;
 
-use crate::hasher::assign_shard_from_parts;
-use crate::parser::{ParsedFamily, extract_metric_name, extract_sorted_label_key};
+use crate::hasher::{HashAlgorithm, ShardLayout, series_hash};
+use crate::parser::{
+    ParseStats, ParsedFamily, extract_metric_name, extract_sorted_label_key, parse_families,
+};
 
 pub type SharedState = Arc<ArcSwap<ShardedState>>;
 
+/// Liveness signal from the scrape loop. The loop beats once per iteration;
+/// `/-/healthy` reports the process wedged once no beat arrived for `max_age`.
+pub struct Heartbeat {
+    last_beat: Mutex<Instant>,
+    max_age: Duration,
+}
+
+impl Heartbeat {
+    /// Starts fresh, so a process is live until its first iteration is overdue.
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            last_beat: Mutex::new(Instant::now()),
+            max_age,
+        }
+    }
+
+    pub fn beat(&self) {
+        *self.last_beat.lock().unwrap() = Instant::now();
+    }
+
+    pub fn age(&self) -> Duration {
+        self.last_beat.lock().unwrap().elapsed()
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.age() > self.max_age
+    }
+}
+
 pub struct ShardedState {
     pub shards: Vec<ShardData>,
+    /// Shards of each configured `[[view]]`, built from the same families.
+    pub views: BTreeMap<String, Vec<ShardData>>,
     pub last_scrape: Instant,
+    /// Wall-clock time of `last_scrape`, used for `Last-Modified`.
+    pub scraped_at: SystemTime,
     pub source_status: Vec<SourceStatus>,
+    /// Series count per label name over everything served, for schema breadth.
+    pub label_names: BTreeMap<String, usize>,
+    /// Served and shadow hashing compared on this scrape's series; empty
+    /// without `shadow_algorithm`.
+    pub algorithm_stats: Vec<AlgorithmStats>,
+    /// Families dropped by `max_families` this cycle.
+    pub families_over_limit: usize,
+    /// Every shard merged into one body for `/metrics/all`, built on first
+    /// request like [`ShardData::binary`].
+    pub all_text: OnceLock<Bytes>,
+}
+
+/// How one hashing algorithm would spread a scrape's series over equal shards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlgorithmStats {
+    pub algorithm: HashAlgorithm,
+    /// Standard deviation over mean of series per shard; 0 is perfect balance.
+    pub balance_cv: f64,
+    /// Fraction of series that would move if one shard were added.
+    pub resize_moved_ratio: f64,
 }
 
 pub struct ShardData {
@@ -22,63 +81,515 @@ pub struct ShardData {
     pub families_count: usize,
     /// Number of individual time series (samples) in this shard.
     pub series_count: usize,
+    /// Strong ETag (quoted xxh3 of `text`), stable for identical content.
+    pub etag: String,
+    /// Binary encoding of `text`, built on first request and kept for the
+    /// lifetime of this scrape's state.
+    pub binary: OnceLock<Bytes>,
+    /// JSON encoding of `text`, built on first request like `binary`.
+    pub json: OnceLock<Bytes>,
+    pub gzip: ShardGzip,
+    /// [`series_hash`] of every series in this shard, kept with
+    /// `track_series_churn` to diff against the next cycle.
+    pub series_keys: Option<HashSet<u64>>,
+    /// Series added and removed since the previous cycle; `None` without
+    /// `track_series_churn` or without a tracked previous shard.
+    pub churn: Option<SeriesChurn>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeriesChurn {
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// How a shard is gzip-encoded for clients that accept it.
+#[derive(Clone)]
+pub enum ShardGzip {
+    /// Compressed per request by the compression middleware (no `gzip_min_bytes`).
+    OnTheFly,
+    /// Compressed once per scrape cycle; the shard is at least `gzip_min_bytes`.
+    Precomputed(Bytes),
+    /// Below `gzip_min_bytes`: always served plain, not worth the CPU.
+    Never,
+}
+
+impl ShardGzip {
+    fn for_text(text: &[u8], gzip_min_bytes: Option<usize>) -> Self {
+        match gzip_min_bytes {
+            None => ShardGzip::OnTheFly,
+            Some(min) if text.len() >= min => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(text).expect("gzip into Vec cannot fail");
+                ShardGzip::Precomputed(Bytes::from(
+                    encoder.finish().expect("gzip into Vec cannot fail"),
+                ))
+            }
+            Some(_) => ShardGzip::Never,
+        }
+    }
+
+    /// Whether this encoding is what [`ShardGzip::for_text`] would produce
+    /// for a text of `len` bytes, so it can be carried into the next cycle.
+    fn fits(&self, len: usize, gzip_min_bytes: Option<usize>) -> bool {
+        match (self, gzip_min_bytes) {
+            (ShardGzip::OnTheFly, None) => true,
+            (ShardGzip::Precomputed(_), Some(min)) => len >= min,
+            (ShardGzip::Never, Some(min)) => len < min,
+            _ => false,
+        }
+    }
 }
 
+/// Family names kept per drop reason, per source and in `/status`
+/// `dropped_samples`.
+pub const DROPPED_FAMILY_SAMPLES: usize = 10;
+
+#[derive(Default)]
 pub struct SourceStatus {
     pub url: String,
     pub success: bool,
+    /// Classification of the last scrape, for telling failure modes apart.
+    pub outcome: ScrapeOutcome,
     pub duration: Duration,
+    /// Part of `duration` spent fetching; the rest is parsing and per-source
+    /// processing.
+    pub fetch_duration: Duration,
     pub metric_families: usize,
+    /// Size of the response body (after transport decompression).
+    pub body_bytes: usize,
+    /// Series contributed after limits and timestamp handling.
+    pub series_count: usize,
+    /// Line counts from parsing the last body.
+    pub parse_stats: ParseStats,
+    /// Series dropped during this scrape, by reason (e.g. `max_labels`).
+    pub dropped_series: BTreeMap<&'static str, usize>,
+    /// Up to [`DROPPED_FAMILY_SAMPLES`] names of families that lost series,
+    /// by the same reasons as `dropped_series`.
+    pub dropped_families: BTreeMap<&'static str, Vec<String>>,
+    /// Samples whose out-of-tolerance timestamp was stripped during this scrape.
+    pub stripped_timestamps: usize,
+}
+
+/// How a source scrape ended.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScrapeOutcome {
+    #[default]
+    Ok,
+    /// TCP/TLS connection could not be established.
+    ConnectError,
+    /// Upstream answered with a non-2xx status.
+    HttpStatus(u16),
+    /// Request or body read exceeded `timeout_secs`.
+    Timeout,
+    /// Response parsed to zero metric families.
+    Empty,
+    /// Upstream answered 204 or one of `empty_statuses`: healthy, nothing
+    /// to export.
+    NoContent,
+    /// Anything else, e.g. an unreadable credential file or a broken body.
+    Other,
+}
+
+impl SourceStatus {
+    pub fn parse_duration(&self) -> Duration {
+        self.duration.saturating_sub(self.fetch_duration)
+    }
+}
+
+impl ScrapeOutcome {
+    /// Stable snake_case name, used as the `kind` label and in `/status`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ScrapeOutcome::Ok => "ok",
+            ScrapeOutcome::ConnectError => "connect_error",
+            ScrapeOutcome::HttpStatus(_) => "http_status",
+            ScrapeOutcome::Timeout => "timeout",
+            ScrapeOutcome::Empty => "empty",
+            ScrapeOutcome::NoContent => "no_content",
+            ScrapeOutcome::Other => "other",
+        }
+    }
+
+    /// Coarse health for alert routing: `up`, `timeout` (reachable but too
+    /// slow), or `down` for every other failure.
+    pub fn health(&self) -> &'static str {
+        match self {
+            ScrapeOutcome::Ok | ScrapeOutcome::NoContent => "up",
+            ScrapeOutcome::Timeout => "timeout",
+            _ => "down",
+        }
+    }
 }
 
 /// Builds pre-rendered shards from parsed metric families.
 ///
-/// Each sample is hashed by `metric_name + sorted_labels` for consistent
-/// per-series distribution. HELP and TYPE headers are emitted into a shard
-/// the first time any series of that family appears there.
-pub fn build_shards(families: Vec<ParsedFamily>, num_shards: u32) -> Vec<ShardData> {
-    let mut shard_texts: Vec<String> = (0..num_shards).map(|_| String::new()).collect();
-    let mut shard_series: Vec<usize> = vec![0; num_shards as usize];
+/// Each sample is hashed by `metric_name + sorted_labels` (only the layout's
+/// hash labels, when set) for consistent per-series distribution, unless the
+/// layout assigns its family as a whole (pins, `shard_by = "family"`). HELP
+/// and TYPE headers are emitted into a shard the first time any series of
+/// that family appears there.
+///
+/// Shards are rendered on up to `compression_threads` threads. With
+/// `gzip_min_bytes` set, shards at least that large are also gzip-compressed
+/// there, once per cycle; smaller ones are never compressed.
+///
+/// A shard whose text hashes the same as the shard at its index in
+/// `previous` reuses that shard's buffers, compressed body and binary and JSON
+/// encodings instead of allocating and compressing them again.
+///
+/// With `track_churn`, each shard keeps its series keys and reports how many
+/// were added and removed relative to the same shard in `previous`.
+pub fn build_shards(
+    families: &[ParsedFamily],
+    layout: &ShardLayout,
+    gzip_min_bytes: Option<usize>,
+    compression_threads: usize,
+    previous: &[ShardData],
+    track_churn: bool,
+) -> Vec<ShardData> {
+    let num_shards = layout.num_shards() as usize;
+    let mut parts: Vec<ShardParts> = (0..num_shards)
+        .map(|_| ShardParts {
+            keys: track_churn.then(HashSet::new),
+            ..Default::default()
+        })
+        .collect();
     // Tracks which (shard_idx, family_name) pairs have had their header written.
     // Uses &str borrowing from `families` to avoid cloning family names.
     let mut headers_written: HashSet<(usize, &str)> = HashSet::new();
 
-    for family in &families {
+    // Assigning series is cheap and stays sequential; rendering and
+    // compressing each shard is the expensive part and runs in parallel.
+    for family in families {
+        // Pinned families, and every family under `shard_by = "family"`,
+        // are assigned once rather than per series.
+        let family_shard = layout.family_shard(&family.name);
         for sample in &family.samples {
-            // Compute hash key inline from raw_line to avoid storing label_key in Sample.
-            let sample_name = extract_metric_name(&sample.raw_line);
-            let label_key = extract_sorted_label_key(&sample.raw_line);
-            // Build hash key without a heap allocation: hash name + NUL + labels directly.
-            let shard_id = assign_shard_from_parts(sample_name, &label_key, num_shards) as usize;
-
-            // Emit HELP/TYPE the first time this family appears in this shard.
-            if !headers_written.contains(&(shard_id, family.name.as_str())) {
-                if let Some(help) = &family.help_line {
-                    shard_texts[shard_id].push_str(help);
+            let shard_id = match family_shard {
+                Some(shard) if !track_churn => shard as usize,
+                _ => {
+                    // Compute hash key inline from raw_line to avoid storing label_key in Sample.
+                    let sample_name = extract_metric_name(&sample.raw_line);
+                    let label_key = extract_sorted_label_key(&sample.raw_line);
+                    // Build hash key without a heap allocation: hash name + NUL + labels directly.
+                    let shard_id = match family_shard {
+                        Some(shard) => shard as usize,
+                        None => {
+                            layout.assign_line(sample_name, &sample.raw_line, &label_key) as usize
+                        }
+                    };
+                    if let Some(keys) = &mut parts[shard_id].keys {
+                        keys.insert(series_hash(sample_name, &label_key, 0));
+                    }
+                    shard_id
                 }
-                if let Some(type_line) = &family.type_line {
-                    shard_texts[shard_id].push_str(type_line);
+            };
+            let shard = &mut parts[shard_id];
+
+            // Emit HELP/TYPE/UNIT the first time this family appears in this shard.
+            if headers_written.insert((shard_id, family.name.as_str())) {
+                let headers = [&family.help_line, &family.type_line, &family.unit_line];
+                for header in headers.into_iter().flatten() {
+                    shard.lines.push(header);
+                    shard.bytes += header.len();
                 }
-                headers_written.insert((shard_id, family.name.as_str()));
+                shard.families += 1;
             }
 
-            shard_texts[shard_id].push_str(&sample.raw_line);
-            shard_series[shard_id] += 1;
+            shard.lines.push(&sample.raw_line);
+            shard.bytes += sample.raw_line.len();
+            shard.series += 1;
         }
     }
 
-    shard_texts
-        .into_iter()
-        .enumerate()
-        .map(|(i, text)| {
-            let families_count = headers_written
+    let jobs: Vec<_> = parts.into_iter().enumerate().collect();
+    parallel_map(jobs, compression_threads, |(i, parts)| {
+        render_shard(parts, previous.get(i), gzip_min_bytes)
+    })
+}
+
+/// One shard's lines in output order, collected before rendering.
+#[derive(Default)]
+struct ShardParts<'a> {
+    lines: Vec<&'a str>,
+    /// Total length of `lines`, so the text is allocated once at full size.
+    bytes: usize,
+    families: usize,
+    series: usize,
+    /// Series keys, when tracking churn.
+    keys: Option<HashSet<u64>>,
+}
+
+/// Renders and compresses one shard, or carries `previous` over when the
+/// rendered text hashes the same.
+fn render_shard(
+    parts: ShardParts<'_>,
+    previous: Option<&ShardData>,
+    gzip_min_bytes: Option<usize>,
+) -> ShardData {
+    let mut text = String::with_capacity(parts.bytes);
+    for line in &parts.lines {
+        text.push_str(line);
+    }
+    let etag = format!("\"{:016x}\"", xxh3_64(text.as_bytes()));
+    let churn = parts.keys.as_ref().and_then(|keys| {
+        let before = previous?.series_keys.as_ref()?;
+        Some(SeriesChurn {
+            added: keys.difference(before).count(),
+            removed: before.difference(keys).count(),
+        })
+    });
+    let reused = previous.filter(|prev| {
+        prev.etag == etag
+            && prev.text.len() == text.len()
+            && prev.gzip.fits(text.len(), gzip_min_bytes)
+    });
+    match reused {
+        Some(prev) => ShardData {
+            text: prev.text.clone(),
+            families_count: parts.families,
+            series_count: parts.series,
+            etag,
+            binary: prev.binary.clone(),
+            json: prev.json.clone(),
+            gzip: prev.gzip.clone(),
+            series_keys: parts.keys,
+            churn,
+        },
+        None => ShardData {
+            gzip: ShardGzip::for_text(text.as_bytes(), gzip_min_bytes),
+            text: Bytes::from(text),
+            families_count: parts.families,
+            series_count: parts.series,
+            etag,
+            binary: OnceLock::new(),
+            json: OnceLock::new(),
+            series_keys: parts.keys,
+            churn,
+        },
+    }
+}
+
+impl ShardData {
+    /// A shard restored from the on-disk cache rather than rendered.
+    pub fn restored(
+        text: Bytes,
+        gzip: ShardGzip,
+        families_count: usize,
+        series_count: usize,
+    ) -> Self {
+        ShardData {
+            etag: format!("\"{:016x}\"", xxh3_64(&text)),
+            text,
+            families_count,
+            series_count,
+            binary: OnceLock::new(),
+            json: OnceLock::new(),
+            gzip,
+            series_keys: None,
+            churn: None,
+        }
+    }
+}
+
+/// Maps `items` on up to `threads` scoped threads, returning results in
+/// input order. Each item is processed independently, so the output is
+/// identical to a serial run whatever the scheduling.
+fn parallel_map<T: Send, R: Send>(
+    items: Vec<T>,
+    threads: usize,
+    f: impl Fn(T) -> R + Sync,
+) -> Vec<R> {
+    let threads = threads.clamp(1, items.len().max(1));
+    if threads == 1 {
+        return items.into_iter().map(f).collect();
+    }
+    let chunk_len = items.len().div_ceil(threads);
+    let mut items = items.into_iter().peekable();
+    let mut chunks = Vec::with_capacity(threads);
+    while items.peek().is_some() {
+        chunks.push(items.by_ref().take(chunk_len).collect::<Vec<_>>());
+    }
+    let f = &f;
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| scope.spawn(move || chunk.into_iter().map(f).collect::<Vec<_>>()))
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("shard rendering panicked"))
+            .collect()
+    })
+}
+
+/// Concatenates several rendered shards into one exposition body.
+///
+/// The combined text is re-grouped by family so each family forms a single
+/// contiguous block with one HELP/TYPE header, even when its series are
+/// spread over several of the given shards.
+pub fn merge_shard_texts(shards: &[ShardData]) -> String {
+    let mut combined = String::with_capacity(shards.iter().map(|s| s.text.len()).sum());
+    for shard in shards {
+        combined.push_str(std::str::from_utf8(&shard.text).unwrap_or_default());
+    }
+
+    render_families(&parse_families(&combined).0)
+}
+
+/// Renders families back to exposition text: HELP, TYPE, UNIT, then samples.
+pub fn render_families(families: &[ParsedFamily]) -> String {
+    let mut out = String::new();
+    for family in families {
+        if let Some(help) = &family.help_line {
+            out.push_str(help);
+        }
+        if let Some(type_line) = &family.type_line {
+            out.push_str(type_line);
+        }
+        if let Some(unit) = &family.unit_line {
+            out.push_str(unit);
+        }
+        for sample in &family.samples {
+            out.push_str(&sample.raw_line);
+        }
+    }
+    out
+}
+
+/// Dry-run projection of what changing `num_shards` would do to the served state.
+pub struct ReshardDiff {
+    pub total_series: usize,
+    /// Series whose shard id differs under the proposed shard count.
+    pub moved_series: usize,
+    /// Per-shard `(current, projected)` series and byte counts, indexed by
+    /// shard id over `0..max(current, proposed)`.
+    pub shards: Vec<ShardDelta>,
+}
+
+#[derive(Default, Clone)]
+pub struct ShardDelta {
+    pub current_series: usize,
+    pub projected_series: usize,
+    pub current_bytes: usize,
+    pub projected_bytes: usize,
+}
+
+impl ReshardDiff {
+    pub fn moved_fraction(&self) -> f64 {
+        if self.total_series == 0 {
+            0.0
+        } else {
+            self.moved_series as f64 / self.total_series as f64
+        }
+    }
+}
+
+/// Re-places every series of the current shards under `layout` resized to
+/// `proposed` shards (see [`ShardLayout::resized`]) without building new
+/// shard data: pinned families stay on their pinned shard, everything else is
+/// re-hashed (on the hash labels only, when set). Only sample bytes are
+/// counted; HELP/TYPE header overhead is not projected. Fails when a pin
+/// targets a shard `>= proposed`.
+pub fn reshard_diff(
+    shards: &[ShardData],
+    layout: &ShardLayout,
+    proposed: u32,
+) -> Result<ReshardDiff, String> {
+    if let Some((re, shard)) = layout.pins().iter().find(|(_, shard)| *shard >= proposed) {
+        return Err(format!(
+            "pin {:?} targets shard {shard}, out of range for num_shards {proposed}",
+            re.as_str()
+        ));
+    }
+    let projected = layout.resized(proposed);
+    let width = shards.len().max(proposed as usize);
+    let mut deltas = vec![ShardDelta::default(); width];
+    let mut total_series = 0;
+    let mut moved_series = 0;
+
+    for (current_id, shard) in shards.iter().enumerate() {
+        let text = std::str::from_utf8(&shard.text).unwrap_or_default();
+        for family in parse_families(text).0 {
+            let family_shard = projected.family_shard(&family.name);
+            for sample in &family.samples {
+                let new_id = match family_shard {
+                    Some(shard) => shard as usize,
+                    None => {
+                        let line = &sample.raw_line;
+                        let name = extract_metric_name(line);
+                        projected.assign_line(name, line, &extract_sorted_label_key(line)) as usize
+                    }
+                };
+                let bytes = sample.raw_line.len();
+
+                total_series += 1;
+                if new_id != current_id {
+                    moved_series += 1;
+                }
+                deltas[current_id].current_series += 1;
+                deltas[current_id].current_bytes += bytes;
+                deltas[new_id].projected_series += 1;
+                deltas[new_id].projected_bytes += bytes;
+            }
+        }
+    }
+
+    Ok(ReshardDiff {
+        total_series,
+        moved_series,
+        shards: deltas,
+    })
+}
+
+/// Assigns every series in `families` to `num_shards` equal shards under each
+/// of `algorithms`, and once more with a shard added, without building any
+/// shard data. Weights, pins, seeds and hash labels of the served layout are
+/// not applied: this compares the algorithms alone.
+pub fn compare_algorithms(
+    families: &[ParsedFamily],
+    num_shards: u32,
+    algorithms: &[HashAlgorithm],
+) -> Vec<AlgorithmStats> {
+    let keys: Vec<u64> = families
+        .iter()
+        .flat_map(|f| &f.samples)
+        .map(|s| {
+            let name = extract_metric_name(&s.raw_line);
+            series_hash(name, &extract_sorted_label_key(&s.raw_line), 0)
+        })
+        .collect();
+    algorithms
+        .iter()
+        .map(|&algorithm| {
+            let mut counts = vec![0usize; num_shards as usize];
+            let mut moved = 0;
+            for &key in &keys {
+                let shard = algorithm.shard(key, num_shards);
+                counts[shard as usize] += 1;
+                if algorithm.shard(key, num_shards + 1) != shard {
+                    moved += 1;
+                }
+            }
+            let mean = keys.len() as f64 / num_shards as f64;
+            let variance = counts
                 .iter()
-                .filter(|(shard_id, _)| *shard_id == i)
-                .count();
-            ShardData {
-                text: Bytes::from(text),
-                families_count,
-                series_count: shard_series[i],
+                .map(|&c| (c as f64 - mean).powi(2))
+                .sum::<f64>()
+                / num_shards as f64;
+            AlgorithmStats {
+                algorithm,
+                balance_cv: if mean > 0.0 {
+                    variance.sqrt() / mean
+                } else {
+                    0.0
+                },
+                resize_moved_ratio: if keys.is_empty() {
+                    0.0
+                } else {
+                    moved as f64 / keys.len() as f64
+                },
             }
         })
         .collect()
@@ -87,7 +598,13 @@ pub fn build_shards(families: Vec<ParsedFamily>, num_shards: u32) -> Vec<ShardDa
 pub fn empty_state() -> Arc<ShardedState> {
     Arc::new(ShardedState {
         shards: Vec::new(),
+        views: BTreeMap::new(),
         last_scrape: Instant::now(),
+        scraped_at: SystemTime::now(),
         source_status: Vec::new(),
+        label_names: BTreeMap::new(),
+        algorithm_stats: Vec::new(),
+        families_over_limit: 0,
+        all_text: OnceLock::new(),
     })
 }
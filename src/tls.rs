@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use anyhow::{Context, ensure};
+use rustls::ServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+use crate::config::TlsConfig;
+
+/// Builds the listener's rustls config from `cert_file` (the certificate
+/// chain, leaf first) and `key_file`. The key may be PKCS#8
+/// (`BEGIN PRIVATE KEY`), PKCS#1 RSA (`BEGIN RSA PRIVATE KEY`) or SEC1 EC.
+pub fn server_config(tls: &TlsConfig) -> anyhow::Result<Arc<ServerConfig>> {
+    let cert_file = tls.cert_file.display();
+    let certs = CertificateDer::pem_file_iter(&tls.cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to load tls cert_file {cert_file}"))?;
+    ensure!(
+        !certs.is_empty(),
+        "tls cert_file {cert_file} holds no certificates"
+    );
+    let key = PrivateKeyDer::from_pem_file(&tls.key_file)
+        .with_context(|| format!("failed to load tls key_file {}", tls.key_file.display()))?;
+
+    // Explicit provider: reqwest's rustls shares the process, so relying on
+    // a process-wide default is fragile.
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .with_context(|| {
+            format!(
+                "tls key_file {} does not match cert_file {cert_file}",
+                tls.key_file.display()
+            )
+        })?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,139 @@
+use std::borrow::Cow;
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A single step of a per-source body transformation pipeline.
+///
+/// Transforms are applied in order to the raw response body before it is
+/// handed to `parse_families`. They are an escape hatch for quirky upstreams,
+/// not a general relabelling mechanism.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transform {
+    /// Drops every line that matches the regex.
+    DropLineRegex(
+        #[serde(
+            deserialize_with = "deserialize_regex",
+            serialize_with = "serialize_regex"
+        )]
+        Regex,
+    ),
+    /// Replaces every match of `regex` in the body with `with`.
+    /// `with` may reference capture groups (`$1`, `${name}`).
+    Replace {
+        #[serde(
+            deserialize_with = "deserialize_regex",
+            serialize_with = "serialize_regex"
+        )]
+        regex: Regex,
+        with: String,
+    },
+}
+
+pub(crate) fn deserialize_regex<'de, D: Deserializer<'de>>(d: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(d)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+/// Like [`deserialize_regex`], but anchors the pattern at both ends so it must
+/// match the whole input, as Prometheus relabelling regexes do.
+pub(crate) fn deserialize_anchored_regex<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(d)?;
+    Regex::new(&format!("^(?:{pattern})$")).map_err(serde::de::Error::custom)
+}
+
+pub(crate) fn serialize_regex<S: Serializer>(re: &Regex, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(re.as_str())
+}
+
+/// Serializes the pattern as written, without the anchors
+/// [`deserialize_anchored_regex`] added.
+pub(crate) fn serialize_anchored_regex<S: Serializer>(re: &Regex, s: S) -> Result<S::Ok, S::Error> {
+    let pattern = re.as_str();
+    let inner = pattern
+        .strip_prefix("^(?:")
+        .and_then(|p| p.strip_suffix(")$"))
+        .unwrap_or(pattern);
+    s.serialize_str(inner)
+}
+
+/// Applies `transforms` to `body` in order. Returns the body unchanged
+/// (without copying) when the pipeline is empty.
+pub fn apply_transforms<'a>(body: &'a str, transforms: &[Transform]) -> Cow<'a, str> {
+    let mut out = Cow::Borrowed(body);
+    for transform in transforms {
+        out = match transform {
+            Transform::DropLineRegex(re) => {
+                let mut kept = String::with_capacity(out.len());
+                for line in out.lines().filter(|l| !re.is_match(l)) {
+                    kept.push_str(line);
+                    kept.push('\n');
+                }
+                Cow::Owned(kept)
+            }
+            Transform::Replace { regex, with } if regex.is_match(&out) => {
+                Cow::Owned(regex.replace_all(&out, with.as_str()).into_owned())
+            }
+            Transform::Replace { .. } => out,
+        };
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml_src: &str) -> Result<Vec<Transform>, toml::de::Error> {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            transforms: Vec<Transform>,
+        }
+        toml::from_str::<Wrapper>(toml_src).map(|w| w.transforms)
+    }
+
+    #[test]
+    fn empty_pipeline_borrows_body() {
+        let body = "up 1\n";
+        assert!(matches!(apply_transforms(body, &[]), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn drop_line_regex_removes_matching_lines() {
+        let transforms = parse(r#"transforms = [{ drop_line_regex = "^# EOF" }]"#).unwrap();
+        let body = "up 1\n# EOF\nfoo 2\n";
+        assert_eq!(apply_transforms(body, &transforms), "up 1\nfoo 2\n");
+    }
+
+    #[test]
+    fn replace_rewrites_text_with_captures() {
+        let transforms =
+            parse(r#"transforms = [{ replace = { regex = "bad_(\\w+)", with = "good_$1" } }]"#)
+                .unwrap();
+        let body = "bad_metric{a=\"1\"} 1\nother 2\n";
+        assert_eq!(
+            apply_transforms(body, &transforms),
+            "good_metric{a=\"1\"} 1\nother 2\n"
+        );
+    }
+
+    #[test]
+    fn transforms_applied_in_order() {
+        let transforms = parse(
+            r#"transforms = [
+                { replace = { regex = "junk", with = "drop_me" } },
+                { drop_line_regex = "drop_me" },
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(apply_transforms("junk 1\nup 1\n", &transforms), "up 1\n");
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected_at_load() {
+        assert!(parse(r#"transforms = [{ drop_line_regex = "(" }]"#).is_err());
+    }
+}
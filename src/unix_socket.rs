@@ -0,0 +1,87 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::header::{HOST, HeaderMap};
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use tokio::net::UnixStream;
+use tokio::time;
+
+use crate::state::ScrapeOutcome;
+
+/// Scheme prefix of a source scraped over a Unix domain socket.
+const UNIX_SCHEME: &str = "unix://";
+
+/// Splits `unix:///run/exporter.sock:/metrics` into the socket path and the
+/// HTTP path (with any query). The socket path ends at the first `:`, so it
+/// can't contain one; the HTTP path must start with `/`.
+pub fn parse_unix_url(url: &str) -> Option<(&Path, &str)> {
+    let rest = url.strip_prefix(UNIX_SCHEME)?;
+    let (socket, path) = rest.split_once(':')?;
+    (socket.starts_with('/') && path.starts_with('/')).then(|| (Path::new(socket), path))
+}
+
+/// Sends a GET for `path` over one HTTP/1.1 connection to `socket` and reads
+/// the whole body. The caller bounds it with the source's timeout; connecting
+/// alone is bounded by `connect_timeout` when given.
+pub async fn get(
+    socket: &Path,
+    path: &str,
+    headers: HeaderMap,
+    connect_timeout: Option<Duration>,
+) -> Result<Response<Bytes>, (ScrapeOutcome, anyhow::Error)> {
+    let connect = UnixStream::connect(socket);
+    let stream = match connect_timeout {
+        Some(limit) => time::timeout(limit, connect).await.map_err(|_| {
+            (
+                ScrapeOutcome::Timeout,
+                anyhow::anyhow!("no connection to {} within {limit:?}", socket.display()),
+            )
+        })?,
+        None => connect.await,
+    }
+    .with_context(|| format!("failed to connect to {}", socket.display()))
+    .map_err(|e| (ScrapeOutcome::ConnectError, e))?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|e| (ScrapeOutcome::ConnectError, e.into()))?;
+    // Drives the connection until the response body has been read.
+    tokio::spawn(conn);
+
+    let mut req = Request::get(path)
+        .header(HOST, "localhost")
+        .body(Empty::<Bytes>::new())
+        .map_err(|e| (ScrapeOutcome::Other, e.into()))?;
+    req.headers_mut().extend(headers);
+    let resp = sender
+        .send_request(req)
+        .await
+        .map_err(|e| (ScrapeOutcome::Other, e.into()))?;
+    let (parts, body) = resp.into_parts();
+    let body = body
+        .collect()
+        .await
+        .map_err(|e| (ScrapeOutcome::Other, e.into()))?
+        .to_bytes();
+    Ok(Response::from_parts(parts, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_url_splits_socket_and_http_path() {
+        assert_eq!(
+            parse_unix_url("unix:///run/exporter.sock:/metrics?x=a:b"),
+            Some((Path::new("/run/exporter.sock"), "/metrics?x=a:b"))
+        );
+        assert_eq!(parse_unix_url("unix:///run/exporter.sock"), None);
+        assert_eq!(parse_unix_url("unix://run.sock:/metrics"), None);
+        assert_eq!(parse_unix_url("unix:///run.sock:metrics"), None);
+        assert_eq!(parse_unix_url("http://host:9100/metrics"), None);
+    }
+}